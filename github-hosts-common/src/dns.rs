@@ -0,0 +1,406 @@
+//! The DNS core of the eBPF program: finding the question of a response and
+//! rewriting the response in place. It is `no_std` and works on any
+//! [`Bytes`], so the program and userspace tools such as `replay` run the
+//! very same code.
+//!
+//! Offsets are from the start of the Ethernet frame. Like the program, only
+//! IPv4 without options is supported.
+
+use crate::{RuleKey, DEFAULT_POLICY, NAME_LEN};
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const IP_HDR_LEN: usize = 20;
+pub const UDP_HDR_LEN: usize = 8;
+pub const DNS_HDR_LEN: usize = 12;
+
+pub const IP_OFF: usize = ETH_HDR_LEN;
+pub const UDP_OFF: usize = IP_OFF + IP_HDR_LEN;
+pub const DNS_OFF: usize = UDP_OFF + UDP_HDR_LEN;
+/// Where the question, and its name, start
+pub const QUESTION_OFF: usize = DNS_OFF + DNS_HDR_LEN;
+
+const ETH_P_IP: [u8; 2] = 0x0800u16.to_be_bytes();
+const IPPROTO_UDP: u8 = 17;
+
+pub const TYPE_A: [u8; 2] = 1u16.to_be_bytes();
+pub const CLASS_IN: [u8; 2] = 1u16.to_be_bytes();
+
+pub const FLAG_QR: u16 = 0x8000;
+pub const RCODE_MASK: u16 = 0x000f;
+pub const RCODE_NXDOMAIN: u16 = 3;
+
+// https://www.rfc-editor.org/rfc/rfc1035 2.3.4. Size limits
+const MAX_NAME_LEN: usize = 255;
+/// Labels of a name followed before giving up, root label excluded: enough
+/// for `github.global.ssl.fastly.net`
+pub const MAX_LABELS: usize = 5;
+/// Answer records looked at for A records
+pub const MAX_ANSWERS: u16 = 20;
+
+/// The bytes of a packet, every access checked against its end, the way the
+/// eBPF verifier wants it.
+pub trait Bytes {
+    /// The `N` bytes at `offset`, `None` past the end of the packet.
+    fn load<const N: usize>(&self, offset: usize) -> Option<[u8; N]>;
+    /// Overwrite the `N` bytes at `offset`, `None` past the end of the packet.
+    fn store<const N: usize>(&mut self, offset: usize, value: [u8; N]) -> Option<()>;
+
+    /// Whether [`nxdomain`] may shorten the message through its IP and UDP
+    /// lengths. An skb keeps its length, and a checksum left to the NIC
+    /// would also cover the records past them.
+    const SHRINK: bool = true;
+
+    /// Overwrite the `old` bytes at `offset`, past the UDP header, with
+    /// `value`, keeping the UDP checksum right. Clearing it, which is
+    /// allowed over IPv4, does for a frame from the wire.
+    #[inline(always)]
+    fn replace<const N: usize>(
+        &mut self,
+        offset: usize,
+        _old: [u8; N],
+        value: [u8; N],
+    ) -> Option<()> {
+        clear_udp_checksum(self)?;
+        self.store(offset, value)
+    }
+}
+
+impl Bytes for [u8] {
+    fn load<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        self.get(offset..offset.checked_add(N)?)?.try_into().ok()
+    }
+
+    fn store<const N: usize>(&mut self, offset: usize, value: [u8; N]) -> Option<()> {
+        self.get_mut(offset..offset.checked_add(N)?)?
+            .copy_from_slice(&value);
+        Some(())
+    }
+}
+
+/// Addresses and ports of a UDP over IPv4 packet. The addresses are as on
+/// the wire, like the keys of the maps, the ports in host byte order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Udp {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub source: u16,
+    pub dest: u16,
+}
+
+/// The UDP header of a packet, `None` for anything else.
+#[inline(always)]
+pub fn udp<B: Bytes + ?Sized>(b: &B) -> Option<Udp> {
+    if b.load::<2>(ETH_HDR_LEN - 2)? != ETH_P_IP || b.load::<1>(IP_OFF + 9)? != [IPPROTO_UDP] {
+        return None;
+    }
+    Some(Udp {
+        saddr: u32::from_ne_bytes(b.load(IP_OFF + 12)?),
+        daddr: u32::from_ne_bytes(b.load(IP_OFF + 16)?),
+        source: u16::from_be_bytes(b.load(UDP_OFF)?),
+        dest: u16::from_be_bytes(b.load(UDP_OFF + 2)?),
+    })
+}
+
+/// The counts of the DNS header, in host byte order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub flags: u16,
+    pub qcount: u16,
+    pub acount: u16,
+}
+
+#[inline(always)]
+pub fn header<B: Bytes + ?Sized>(b: &B) -> Option<Header> {
+    Some(Header {
+        flags: u16::from_be_bytes(b.load(DNS_OFF + 2)?),
+        qcount: u16::from_be_bytes(b.load(DNS_OFF + 4)?),
+        acount: u16::from_be_bytes(b.load(DNS_OFF + 6)?),
+    })
+}
+
+/// Length of the name at `offset`, root label included: a compression
+/// pointer ending it counts as its 2 bytes. `None` for a name with more than
+/// [`MAX_LABELS`] labels, which no rule can be for, or longer than any name
+/// can be, which also bounds the offsets computed from it.
+#[inline(always)]
+pub fn name_len<B: Bytes + ?Sized>(b: &B, offset: usize) -> Option<usize> {
+    let mut j = offset;
+    for _ in 0..MAX_LABELS + 1 {
+        let [len] = b.load::<1>(j)?;
+        if len & 0xc0 == 0xc0 {
+            b.load::<1>(j + 1)?;
+            j += 2;
+        } else {
+            j += 1 + len as usize;
+        }
+        if len == 0 || len & 0xc0 == 0xc0 {
+            let len = j - offset;
+            if len > MAX_NAME_LEN {
+                return None;
+            }
+            return Some(len);
+        }
+    }
+    None
+}
+
+/// The question name of `q_len` bytes, as a `GITHUB_HOSTS` key of the
+/// default policy.
+#[inline(always)]
+pub fn question_key<B: Bytes + ?Sized>(b: &B, q_len: usize) -> Option<RuleKey> {
+    let mut key = RuleKey {
+        policy: DEFAULT_POLICY,
+        name: [0; NAME_LEN],
+    };
+    for i in 0..MAX_NAME_LEN {
+        if i >= q_len {
+            break;
+        }
+        key.name[i] = b.load::<1>(QUESTION_OFF + i)?[0];
+    }
+    Some(key)
+}
+
+/// Zero the UDP checksum, which is optional over IPv4, of a packet about to
+/// change.
+#[inline(always)]
+pub fn clear_udp_checksum<B: Bytes + ?Sized>(b: &mut B) -> Option<()> {
+    b.store(UDP_OFF + 6, [0; 2])
+}
+
+/// Recompute the checksum of the IP header.
+#[inline(always)]
+pub fn set_ip_checksum<B: Bytes + ?Sized>(b: &mut B) -> Option<()> {
+    b.store(IP_OFF + 10, [0; 2])?;
+    let mut sum = 0u32;
+    for i in 0..IP_HDR_LEN / 2 {
+        sum += u16::from_be_bytes(b.load(IP_OFF + 2 * i)?) as u32;
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    b.store(IP_OFF + 10, (!(sum as u16)).to_be_bytes())
+}
+
+/// Walk the answers of a response whose question name is `q_len` bytes
+/// long, setting the address of every A record to `addr`, unless `dry_run`:
+/// a client could otherwise pick one that was left alone. Returns the
+/// original address of the first A record, `None` if there is none or the
+/// answers are malformed.
+#[inline(always)]
+pub fn rewrite_a<B: Bytes + ?Sized>(
+    b: &mut B,
+    q_len: usize,
+    acount: u16,
+    addr: [u8; 4],
+    dry_run: bool,
+) -> Option<[u8; 4]> {
+    // past the name, type and class of the question
+    let mut j = QUESTION_OFF + q_len + 4;
+    let mut old = None;
+    for i in 0..MAX_ANSWERS {
+        if i >= acount {
+            break;
+        }
+        j += name_len(b, j)?;
+        let rtype = b.load::<2>(j)?;
+        // type, class and ttl
+        j += 8;
+        let rdlen = u16::from_be_bytes(b.load(j)?);
+        j += 2;
+        if rtype == TYPE_A && rdlen == 4 {
+            let current = b.load::<4>(j)?;
+            if old.is_none() {
+                old = Some(current);
+            }
+            if !dry_run {
+                b.replace(j, current, addr)?;
+            }
+        }
+        // keeps the offset bounded for the verifier
+        if rdlen > 255 {
+            break;
+        }
+        j += rdlen as usize;
+    }
+    old
+}
+
+/// Turn the response into an NXDOMAIN one ending with its question, whose
+/// name is `q_len` bytes long. Returns the new length of the frame: the
+/// records are left in the packet past it, the receiving IP stack trims
+/// them. Unless [`Bytes::SHRINK`], the IP and UDP lengths still cover the
+/// records, which resolvers skip as the counts leave them out.
+#[inline(always)]
+pub fn nxdomain<B: Bytes + ?Sized>(b: &mut B, q_len: usize) -> Option<usize> {
+    let end = QUESTION_OFF + q_len + 4;
+    let flags = b.load(DNS_OFF + 2)?;
+    let nx = u16::from_be_bytes(flags) & !RCODE_MASK | RCODE_NXDOMAIN;
+    b.replace(DNS_OFF + 2, flags, nx.to_be_bytes())?;
+    // answer, authority and additional counts
+    let counts = b.load(DNS_OFF + 6)?;
+    b.replace(DNS_OFF + 6, counts, [0; 6])?;
+    if !B::SHRINK {
+        return Some(end);
+    }
+    b.store(UDP_OFF + 4, ((end - UDP_OFF) as u16).to_be_bytes())?;
+    clear_udp_checksum(b)?;
+    b.store(IP_OFF + 2, ((end - IP_OFF) as u16).to_be_bytes())?;
+    set_ip_checksum(b)?;
+    Some(end)
+}
+
+/// A response to an A query for `name`, as encoded, from 8.8.8.8 to
+/// `client`, with one A record per address, each pointing back at the
+/// question name. For tests.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+pub fn response(name: &[u8], client: [u8; 4], addrs: &[[u8; 4]]) -> Vec<u8> {
+    let mut dns = vec![
+        0x12,
+        0x34,
+        0x81,
+        0x80,
+        0,
+        1,
+        0,
+        addrs.len() as u8,
+        0,
+        0,
+        0,
+        0,
+    ];
+    dns.extend(name);
+    dns.extend([0, 1, 0, 1]);
+    for addr in addrs {
+        dns.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        dns.extend(addr);
+    }
+    let mut frame = vec![0; ETH_HDR_LEN - 2];
+    frame.extend(ETH_P_IP);
+    let ip_len = (IP_HDR_LEN + UDP_HDR_LEN + dns.len()) as u16;
+    frame.extend([0x45, 0]);
+    frame.extend(ip_len.to_be_bytes());
+    frame.extend([0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0, 8, 8, 8, 8]);
+    frame.extend(client);
+    frame.extend(53u16.to_be_bytes());
+    frame.extend(5353u16.to_be_bytes());
+    frame.extend((ip_len - IP_HDR_LEN as u16).to_be_bytes());
+    frame.extend([0xab, 0xcd]);
+    frame.extend(dns);
+    set_ip_checksum(&mut frame[..]).unwrap();
+    frame
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const CLIENT: [u8; 4] = [10, 0, 0, 2];
+
+    #[test]
+    fn test_question() {
+        let frame = response(b"\x06github\x03com\x00", CLIENT, &[[20, 205, 243, 166]]);
+        assert_eq!(
+            udp(&frame[..]),
+            Some(Udp {
+                saddr: u32::from_ne_bytes([8, 8, 8, 8]),
+                daddr: u32::from_ne_bytes([10, 0, 0, 2]),
+                source: 53,
+                dest: 5353,
+            })
+        );
+        let header = header(&frame[..]).unwrap();
+        assert_eq!((header.qcount, header.acount), (1, 1));
+        let q_len = name_len(&frame[..], QUESTION_OFF).unwrap();
+        assert_eq!(q_len, 12);
+        let key = question_key(&frame[..], q_len).unwrap();
+        assert_eq!(&key.name[..13], b"\x06github\x03com\x00\x00");
+
+        // truncated in the middle of the name
+        assert_eq!(name_len(&frame[..QUESTION_OFF + 4], QUESTION_OFF), None);
+
+        let frame = response(b"\x01a\x01b\x01c\x01d\x01e\x00", CLIENT, &[]);
+        assert_eq!(name_len(&frame[..], QUESTION_OFF), Some(11));
+        // one label too many: not the name `a.b.c.d.e` with a suffix
+        let frame = response(b"\x01a\x01b\x01c\x01d\x01e\x04evil\x00", CLIENT, &[]);
+        assert_eq!(name_len(&frame[..], QUESTION_OFF), None);
+        // labels ending with a compression pointer
+        let frame = response(b"\x03www\xc0\x0c", CLIENT, &[]);
+        assert_eq!(name_len(&frame[..], QUESTION_OFF), Some(6));
+        assert_eq!(udp(&frame[..UDP_OFF]), None);
+    }
+
+    #[test]
+    fn test_rewrite_a() {
+        let mut frame = response(
+            b"\x06github\x03com\x00",
+            CLIENT,
+            &[[1, 1, 1, 1], [2, 2, 2, 2]],
+        );
+        let original = frame.clone();
+        let addr = [140, 82, 113, 4];
+        assert_eq!(
+            rewrite_a(&mut frame[..], 12, 2, addr, true),
+            Some([1, 1, 1, 1])
+        );
+        assert_eq!(frame, original);
+
+        assert_eq!(
+            rewrite_a(&mut frame[..], 12, 2, addr, false),
+            Some([1, 1, 1, 1])
+        );
+        let end = frame.len();
+        assert_eq!(frame[end - 16 - 4..end - 16], addr);
+        assert_eq!(frame[end - 4..], addr);
+        assert_eq!(frame[UDP_OFF + 6..UDP_OFF + 8], [0, 0]);
+
+        let mut frame = response(b"\x06github\x03com\x00", CLIENT, &[]);
+        assert_eq!(rewrite_a(&mut frame[..], 12, 0, addr, false), None);
+    }
+
+    #[test]
+    fn test_nxdomain() {
+        let mut frame = response(b"\x06github\x03com\x00", CLIENT, &[[1, 1, 1, 1]]);
+        let end = nxdomain(&mut frame[..], 12).unwrap();
+        assert_eq!(end, QUESTION_OFF + 16);
+        let header = header(&frame[..]).unwrap();
+        assert_eq!(header.flags & RCODE_MASK, RCODE_NXDOMAIN);
+        assert_eq!(header.acount, 0);
+        assert_eq!(
+            u16::from_be_bytes(frame.load(IP_OFF + 2).unwrap()) as usize,
+            end - IP_OFF
+        );
+        // already up to date
+        let check = frame[IP_OFF + 10..IP_OFF + 12].to_vec();
+        set_ip_checksum(&mut frame[..]).unwrap();
+        assert_eq!(frame[IP_OFF + 10..IP_OFF + 12], check);
+    }
+
+    /// Bytes that can't be shortened, like an skb.
+    struct Skb(Vec<u8>);
+
+    impl Bytes for Skb {
+        const SHRINK: bool = false;
+
+        fn load<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+            self.0[..].load(offset)
+        }
+
+        fn store<const N: usize>(&mut self, offset: usize, value: [u8; N]) -> Option<()> {
+            self.0[..].store(offset, value)
+        }
+    }
+
+    #[test]
+    fn test_nxdomain_no_shrink() {
+        let frame = response(b"\x06github\x03com\x00", CLIENT, &[[1, 1, 1, 1]]);
+        let mut skb = Skb(frame.clone());
+        assert_eq!(nxdomain(&mut skb, 12), Some(QUESTION_OFF + 16));
+        let header = header(&skb).unwrap();
+        assert_eq!(header.flags & RCODE_MASK, RCODE_NXDOMAIN);
+        assert_eq!(header.acount, 0);
+        // lengths and the record are left alone
+        assert_eq!(skb.0[IP_OFF..UDP_OFF + 6], frame[IP_OFF..UDP_OFF + 6]);
+        assert_eq!(skb.0[QUESTION_OFF..], frame[QUESTION_OFF..]);
+    }
+}
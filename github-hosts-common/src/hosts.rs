@@ -0,0 +1,464 @@
+//! Parser for hosts(5) style files such as the ones published by ineo6/hosts.
+//!
+//! Each line is `<address> <hostname> [<hostname>...]`, fields separated by
+//! any amount of spaces or tabs, and everything after a `#` is a comment.
+//! Malformed lines never abort parsing: they are reported as line-numbered
+//! [`Diagnostic`]s and skipped so one bad entry does not take down the rest
+//! of the file.
+//!
+//! A comment line starting with `#@` is a directive instead:
+//!
+//! - `#@policy <id>` scopes the entries that follow to the clients of policy
+//!   `<id>`, up to the next `#@policy`. Entries before any are for
+//!   [`DEFAULT_POLICY`].
+//! - `#@active <HH:MM-HH:MM>` makes the entries that follow apply during that
+//!   [`Window`] of the day only, up to the next `#@active`. `#@active always`
+//!   goes back to entries that always apply.
+//! - `#@candidates on` makes the addresses a host is listed with in the
+//!   entries that follow all candidates for it, up to `#@candidates off`.
+//!   Otherwise a later entry for a host replaces the earlier ones.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::DEFAULT_POLICY;
+
+/// One `address hostname` pair. A line listing several hostnames yields one
+/// entry per hostname, all with the same `line`. A hostname may appear on
+/// several lines with different addresses, the last one winning unless they
+/// are [`Entry::candidate`]s.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// 1-based line number
+    pub line: usize,
+    pub addr: IpAddr,
+    pub host: String,
+    /// Set by the last `#@policy` directive
+    pub policy: u32,
+    /// Set by the last `#@active` directive
+    pub window: Option<Window>,
+    /// Within a `#@candidates on` block: added to the earlier addresses of
+    /// the host rather than replacing them
+    pub candidate: bool,
+}
+
+/// A daily time window, in local time. A window ending before it starts
+/// spans midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Minutes since midnight, included
+    pub start: u16,
+    /// Minutes since midnight, excluded
+    pub end: u16,
+}
+
+impl Window {
+    /// Whether `minute`, in minutes since midnight, is in the window.
+    pub fn contains(&self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Option<u16> {
+    let (h, m) = s.split_once(':')?;
+    if h.len() != 2 || m.len() != 2 {
+        return None;
+    }
+    let (h, m): (u16, u16) = (h.parse().ok()?, m.parse().ok()?);
+    if h > 24 || m > 59 || (h == 24 && m != 0) {
+        return None;
+    }
+    Some(h * 60 + m)
+}
+
+/// `HH:MM-HH:MM`, `24:00` being accepted as an end.
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time window `{}`, expected `HH:MM-HH:MM`", s);
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = parse_time(start)
+            .filter(|m| *m < 24 * 60)
+            .ok_or_else(invalid)?;
+        let end = parse_time(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("empty time window `{}`", s));
+        }
+        Ok(Window { start, end })
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// 1-based line number
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn warning(line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            line,
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            line,
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.line, self.severity, self.message)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostsFile {
+    pub entries: Vec<Entry>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl HostsFile {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+}
+
+/// Parse the content of a hosts file.
+pub fn parse(input: &str) -> HostsFile {
+    let mut file = HostsFile::default();
+    // (policy, host) -> (line, addr) of its latest definition, to flag
+    // conflicting duplicates
+    let mut seen: HashMap<(u32, String), (usize, IpAddr)> = HashMap::new();
+    // (policy, host, addr) -> line of the candidate, to flag duplicates
+    let mut listed: HashMap<(u32, String, IpAddr), usize> = HashMap::new();
+    let mut policy = DEFAULT_POLICY;
+    let mut window = None;
+    let mut candidate = false;
+
+    for (n, line) in input.lines().enumerate() {
+        let n = n + 1;
+        if let Some(directive) = line.trim_start().strip_prefix("#@") {
+            let mut fields = directive.split_whitespace();
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("policy"), Some(id), None) => match id.parse() {
+                    Ok(id) => policy = id,
+                    Err(_) => file
+                        .diagnostics
+                        .push(Diagnostic::error(n, format!("invalid policy `{}`", id))),
+                },
+                (Some("active"), Some("always"), None) => window = None,
+                (Some("active"), Some(w), None) => match w.parse() {
+                    Ok(w) => window = Some(w),
+                    Err(e) => file.diagnostics.push(Diagnostic::error(n, e)),
+                },
+                (Some("candidates"), Some("on"), None) => candidate = true,
+                (Some("candidates"), Some("off"), None) => candidate = false,
+                _ => file.diagnostics.push(Diagnostic::warning(
+                    n,
+                    format!("unknown directive `#@{}`", directive.trim_end()),
+                )),
+            }
+            continue;
+        }
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let mut fields = line.split_whitespace();
+        let addr = match fields.next() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let addr = match addr.parse::<IpAddr>() {
+            Ok(addr) => addr,
+            Err(_) => {
+                file.diagnostics
+                    .push(Diagnostic::error(n, format!("invalid address `{}`", addr)));
+                continue;
+            }
+        };
+        let mut hosts = fields.peekable();
+        if hosts.peek().is_none() {
+            file.diagnostics.push(Diagnostic::error(
+                n,
+                format!("missing hostname after `{}`", addr),
+            ));
+            continue;
+        }
+        for host in hosts {
+            if let Some(c) = host.chars().find(|c| !valid_host_char(*c)) {
+                file.diagnostics.push(Diagnostic::error(
+                    n,
+                    format!("invalid character {:?} in hostname `{}`", c, host),
+                ));
+                continue;
+            }
+            let host = host.to_ascii_lowercase();
+            if candidate {
+                if let Some(first) = listed.get(&(policy, host.clone(), addr)) {
+                    file.diagnostics.push(Diagnostic::warning(
+                        n,
+                        format!(
+                            "duplicate entry {} `{}`, first defined on line {}",
+                            addr, host, first
+                        ),
+                    ));
+                    continue;
+                }
+                listed.insert((policy, host.clone(), addr), n);
+            }
+            if let Some((prev_line, prev_addr)) = seen.insert((policy, host.clone()), (n, addr)) {
+                if !candidate && prev_addr != addr && prev_addr.is_ipv4() == addr.is_ipv4() {
+                    file.diagnostics.push(Diagnostic::warning(
+                        n,
+                        format!(
+                            "`{}` redefined as {}, previously {} on line {}",
+                            host, addr, prev_addr, prev_line
+                        ),
+                    ));
+                }
+            }
+            file.entries.push(Entry {
+                line: n,
+                addr,
+                host,
+                policy,
+                window,
+                candidate,
+            });
+        }
+    }
+    file
+}
+
+/// Letters, digits, `-`, `_` and `.`; non-ASCII is let through for IDNA names.
+fn valid_host_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' || !c.is_ascii()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = parse(
+            "# GitHub Host Start\n\
+             \n\
+             140.82.113.4\tgithub.com   # the main site\n\
+             185.199.108.153 \t assets-cdn.github.com github.io\n\
+             2606:50c0:8000::154   github.io\n",
+        );
+        assert_eq!(file.diagnostics, vec![]);
+        assert_eq!(
+            file.entries,
+            vec![
+                Entry {
+                    line: 3,
+                    addr: addr("140.82.113.4"),
+                    host: "github.com".into(),
+                    policy: DEFAULT_POLICY,
+                    window: None,
+                    candidate: false,
+                },
+                Entry {
+                    line: 4,
+                    addr: addr("185.199.108.153"),
+                    host: "assets-cdn.github.com".into(),
+                    policy: DEFAULT_POLICY,
+                    window: None,
+                    candidate: false,
+                },
+                Entry {
+                    line: 4,
+                    addr: addr("185.199.108.153"),
+                    host: "github.io".into(),
+                    policy: DEFAULT_POLICY,
+                    window: None,
+                    candidate: false,
+                },
+                Entry {
+                    line: 5,
+                    addr: addr("2606:50c0:8000::154"),
+                    host: "github.io".into(),
+                    policy: DEFAULT_POLICY,
+                    window: None,
+                    candidate: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let file = parse(
+            "256.1.1.1 github.com\n\
+             140.82.113.4\n\
+             github.com 140.82.113.4\n\
+             1.2.3 github.com\n\
+             140.82.113.4 *.github.com gist.github.com\n\
+             140.82.113.5 gist.github.com\n",
+        );
+        assert_eq!(
+            file.diagnostics,
+            vec![
+                Diagnostic::error(1, "invalid address `256.1.1.1`"),
+                Diagnostic::error(2, "missing hostname after `140.82.113.4`"),
+                Diagnostic::error(3, "invalid address `github.com`"),
+                Diagnostic::error(4, "invalid address `1.2.3`"),
+                Diagnostic::error(5, "invalid character '*' in hostname `*.github.com`"),
+                Diagnostic::warning(
+                    6,
+                    "`gist.github.com` redefined as 140.82.113.5, previously 140.82.113.4 on line 5"
+                ),
+            ]
+        );
+        assert!(file.has_errors());
+        assert_eq!(file.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_policy() {
+        let file = parse(
+            "140.82.113.4 github.com\n\
+             #@policy 2\n\
+             140.82.113.4 github.com\n\
+             #@policy ci\n\
+             #@frobnicate\n\
+             140.82.114.4 github.com\n\
+             #@policy 0\n\
+             140.82.113.4 github.com\n",
+        );
+        assert_eq!(
+            file.diagnostics,
+            vec![
+                Diagnostic::error(4, "invalid policy `ci`"),
+                Diagnostic::warning(5, "unknown directive `#@frobnicate`"),
+                Diagnostic::warning(
+                    6,
+                    "`github.com` redefined as 140.82.114.4, previously 140.82.113.4 on line 3"
+                ),
+            ]
+        );
+        let policies: Vec<(usize, u32)> = file.entries.iter().map(|e| (e.line, e.policy)).collect();
+        // another address for another policy is no redefinition
+        assert_eq!(policies, [(1, 0), (3, 2), (6, 2), (8, 0)]);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let file = parse(
+            "#@candidates on\n\
+             140.82.112.4 github.com\n\
+             140.82.113.4 github.com\n\
+             140.82.112.4 github.com\n\
+             #@candidates off\n\
+             140.82.114.4 github.com\n",
+        );
+        assert_eq!(
+            file.diagnostics,
+            vec![
+                Diagnostic::warning(
+                    4,
+                    "duplicate entry 140.82.112.4 `github.com`, first defined on line 2"
+                ),
+                Diagnostic::warning(
+                    6,
+                    "`github.com` redefined as 140.82.114.4, previously 140.82.113.4 on line 3"
+                ),
+            ]
+        );
+        let candidates: Vec<(usize, bool)> =
+            file.entries.iter().map(|e| (e.line, e.candidate)).collect();
+        assert_eq!(candidates, [(2, true), (3, true), (6, false)]);
+    }
+
+    #[test]
+    fn test_window() {
+        let file = parse(
+            "#@active 09:00-18:00\n\
+             140.82.113.4 github.com\n\
+             #@active 9-18\n\
+             140.82.113.4 api.github.com\n\
+             #@active always\n\
+             140.82.113.4 gist.github.com\n",
+        );
+        assert_eq!(
+            file.diagnostics,
+            vec![Diagnostic::error(
+                3,
+                "invalid time window `9-18`, expected `HH:MM-HH:MM`"
+            )]
+        );
+        let day: Window = "09:00-18:00".parse().unwrap();
+        let windows: Vec<Option<Window>> = file.entries.iter().map(|e| e.window).collect();
+        // a bad directive leaves the previous window in place
+        assert_eq!(windows, [Some(day), Some(day), None]);
+
+        assert!(day.contains(9 * 60));
+        assert!(!day.contains(18 * 60));
+        assert_eq!(day.to_string(), "09:00-18:00");
+        let night: Window = "22:30-06:00".parse().unwrap();
+        assert!(night.contains(23 * 60) && night.contains(0) && !night.contains(12 * 60));
+        assert_eq!("18:00-24:00".parse::<Window>().unwrap().end, 24 * 60);
+        assert!("24:00-01:00".parse::<Window>().is_err());
+        assert!("10:00-10:00".parse::<Window>().is_err());
+        assert!("10:60-11:00".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let d = Diagnostic::error(7, "invalid address `x`");
+        assert_eq!(d.to_string(), "7: error: invalid address `x`");
+    }
+}
@@ -1,13 +1,307 @@
-#![no_std]
-
-//#[repr(C)]
-//#[derive(Clone, Copy)]
-//pub struct BackendPorts {
-// 四个端口
-// 需要对齐u32，不然会报错过不了verifier：invalid indirect read from stack
-//   pub ports: [u16; 4],
-//  pub index: usize,
-//}
-
-//#[cfg(feature = "user")]
-//unsafe impl aya::Pod for BackendPorts {}
+//! The ABI shared by the eBPF program and userspace: map indices, flags and
+//! the `#[repr(C)]` keys and values of the maps, which implement `aya::Pod`
+//! with the `user` feature.
+//!
+//! | map            | key                     | value           |
+//! |----------------|-------------------------|-----------------|
+//! | `GITHUB_HOSTS` | [`RuleKey`]             | [`RuleValue`]   |
+//! | `CLIENTS`      | subnet, LPM trie        | policy, `u32`   |
+//! | `STATS`        | `STAT_*`                | `u64`, per CPU  |
+//! | `CONFIG`       | `CONFIG_*`              | `u32`           |
+//! | `QUERIES`      | [`QueryKey`]            | `u64`, sent at  |
+//! | `RTT`          | resolver address, `u32` | [`RttHistogram`]|
+//! | `EVENTS`       | perf buffer             | [`RuleEvent`]   |
+//!
+//! All addresses are in network byte order.
+//!
+//! `CONFIG` is an array of `u32` on purpose rather than one `#[repr(C)]`
+//! struct: each setting is a single word at a fixed index, so a setting is
+//! added with a new `CONFIG_*` index or `FLAG_*` bit without moving the
+//! others, and either side reads one word without a struct layout to agree
+//! on.
+//!
+//! [`dns`] parses and rewrites the responses, on any byte buffer: the program
+//! runs it on packets, `github-hosts replay` on the frames of a capture.
+//!
+//! With the `std` feature, [`name`] turns hostnames into the names the
+//! program looks up and back, [`rule`] parses rules given on the command line
+//! and [`hosts`] parses hosts files, for other tools to build map content the
+//! way the daemon does.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod dns;
+#[cfg(feature = "std")]
+pub mod hosts;
+#[cfg(feature = "std")]
+pub mod name;
+#[cfg(feature = "std")]
+pub mod rule;
+
+// Indices into the `STATS` per-CPU array of counters
+/// Packets seen by the program
+pub const STAT_PACKETS: u32 = 0;
+/// UDP packets from port 53
+pub const STAT_DNS_RESPONSES: u32 = 1;
+/// DNS responses whose question has a rule
+pub const STAT_MATCHED: u32 = 2;
+/// DNS responses whose A record was rewritten
+pub const STAT_REWRITTEN: u32 = 3;
+/// Queries answered by the program itself, in authoritative mode
+pub const STAT_ANSWERED: u32 = 4;
+/// DNS queries sent, recorded in `QUERIES`
+pub const STAT_QUERIES: u32 = 5;
+/// Matched responses left alone because they answer no query in `QUERIES`
+pub const STAT_UNSOLICITED: u32 = 6;
+/// Responses or answers for a sinkholed or NXDOMAIN host
+pub const STAT_BLOCKED: u32 = 7;
+/// Responses that would have been rewritten or blocked, left alone in
+/// dry-run mode
+pub const STAT_DRY_RUN: u32 = 8;
+pub const STAT_MAX: u32 = 9;
+
+/// Names of the `STATS` counters, by index
+pub const STAT_NAMES: [&str; STAT_MAX as usize] = [
+    "packets",
+    "dns_responses",
+    "matched",
+    "rewritten",
+    "answered",
+    "queries",
+    "unsolicited",
+    "blocked",
+    "dry_run",
+];
+
+// Indices into the `CONFIG` array, written by userspace
+/// Bitwise or of the `FLAG_*` below
+pub const CONFIG_FLAGS: u32 = 0;
+pub const CONFIG_MAX: u32 = 1;
+
+/// Answer matching A queries from XDP instead of patching upstream responses
+pub const FLAG_AUTHORITATIVE: u32 = 1 << 0;
+/// Only rewrite responses to a query recorded in `QUERIES`
+pub const FLAG_VERIFY: u32 = 1 << 1;
+/// Time the responses to queries recorded in `QUERIES` into `RTT`
+pub const FLAG_RTT: u32 = 1 << 2;
+/// Evaluate the rules and report what would be done on `EVENTS`, without
+/// changing any packet
+pub const FLAG_DRY_RUN: u32 = 1 << 3;
+/// Let every packet through untouched, the kill switch
+pub const FLAG_BYPASS: u32 = 1 << 4;
+
+/// Policy of the clients not in `CLIENTS`, and of the rules that apply to
+/// every client without a rule of its own policy.
+pub const DEFAULT_POLICY: u32 = 0;
+
+/// Size of the name in a [`RuleKey`], which holds any DNS name: those are at
+/// most 255 bytes on the wire.
+pub const NAME_LEN: usize = 256;
+
+/// Key of the `GITHUB_HOSTS` map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RuleKey {
+    /// Policy of the clients the rule is for, from the `CLIENTS` LPM trie
+    pub policy: u32,
+    /// Question name in DNS wire format, zero padded
+    pub name: [u8; NAME_LEN],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RuleKey {}
+
+/// What is done to a response whose question has a rule.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Replace the address of every A record with the rule's
+    Rewrite = 0,
+    /// Like `Rewrite`, with `0.0.0.0`
+    Sinkhole = 1,
+    /// Turn the response into an NXDOMAIN one, without any record
+    Nxdomain = 2,
+    /// Leave the response alone
+    Pass = 3,
+}
+
+/// Value of the `GITHUB_HOSTS` map.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuleValue {
+    /// An [`Action`], kept as a plain integer so that any map content is a
+    /// valid value
+    pub action: u32,
+    /// Address of the A records, for `Rewrite` and `Sinkhole`
+    pub addr: [u8; 4],
+}
+
+impl RuleValue {
+    pub const fn new(action: Action, addr: [u8; 4]) -> Self {
+        RuleValue {
+            action: action as u32,
+            addr,
+        }
+    }
+
+    /// `None` for an action this version doesn't know about.
+    pub fn action(&self) -> Option<Action> {
+        match self.action {
+            0 => Some(Action::Rewrite),
+            1 => Some(Action::Sinkhole),
+            2 => Some(Action::Nxdomain),
+            3 => Some(Action::Pass),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RuleValue {}
+
+/// An address from a hosts file or the command line, `0.0.0.0` being the
+/// usual way of blocking a host.
+#[cfg(feature = "std")]
+impl From<std::net::Ipv4Addr> for RuleValue {
+    fn from(ip: std::net::Ipv4Addr) -> Self {
+        if ip.is_unspecified() {
+            RuleValue::new(Action::Sinkhole, ip.octets())
+        } else {
+            RuleValue::new(Action::Rewrite, ip.octets())
+        }
+    }
+}
+
+/// The address, `nxdomain` or `pass`, as accepted by [`str::parse`].
+#[cfg(feature = "std")]
+impl std::fmt::Display for RuleValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.action() {
+            Some(Action::Rewrite | Action::Sinkhole) => {
+                write!(f, "{}", std::net::Ipv4Addr::from(self.addr))
+            }
+            Some(Action::Nxdomain) => f.write_str("nxdomain"),
+            Some(Action::Pass) => f.write_str("pass"),
+            None => write!(f, "<action {}>", self.action),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::str::FromStr for RuleValue {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(RuleValue::new(Action::Nxdomain, [0; 4])),
+            "pass" => Ok(RuleValue::new(Action::Pass, [0; 4])),
+            _ => s
+                .parse::<std::net::Ipv4Addr>()
+                .map(RuleValue::from)
+                .map_err(|e| {
+                    format!(
+                        "`{}` is neither an IPv4 address, `nxdomain` nor `pass`: {}",
+                        s, e
+                    )
+                }),
+        }
+    }
+}
+
+/// Sent on the `EVENTS` perf buffer for every response the program would
+/// have changed, in dry-run mode.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RuleEvent {
+    /// Question name and the policy of the rule that matched
+    pub key: RuleKey,
+    pub rule: RuleValue,
+    /// Client the response is for, as on the wire
+    pub client: u32,
+    /// Address of the first A record, zero if there is none
+    pub old: [u8; 4],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RuleEvent {}
+
+/// Key of the `QUERIES` map of outstanding queries, all fields as on the wire.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryKey {
+    pub resolver: u32,
+    pub client: u32,
+    pub client_port: u16,
+    pub txid: u16,
+    /// FNV-1a hash of the question name
+    pub qhash: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for QueryKey {}
+
+/// Buckets of [`RttHistogram`]: bucket 0 counts responses that took under
+/// 1µs, bucket `i` the ones that took under 2^i µs, the last one all slower
+/// ones.
+pub const RTT_BUCKETS: usize = 24;
+
+/// Value of the per-CPU `RTT` map, keyed by resolver address.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RttHistogram {
+    pub buckets: [u64; RTT_BUCKETS],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RttHistogram {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    /// The eBPF program and userspace are built separately, a change here
+    /// must be a deliberate one.
+    #[test]
+    fn test_layout() {
+        use core::mem::{offset_of, size_of};
+        assert_eq!(size_of::<RuleKey>(), 4 + NAME_LEN);
+        assert_eq!(size_of::<RuleValue>(), 8);
+        assert_eq!(size_of::<QueryKey>(), 16);
+        assert_eq!(size_of::<RttHistogram>(), 8 * RTT_BUCKETS);
+        assert_eq!(size_of::<RuleEvent>(), 4 + NAME_LEN + 8 + 4 + 4);
+
+        assert_eq!(offset_of!(RuleKey, name), 4);
+        assert_eq!(offset_of!(RuleValue, addr), 4);
+        assert_eq!(offset_of!(RuleEvent, rule), 4 + NAME_LEN);
+        assert_eq!(offset_of!(RuleEvent, client), 4 + NAME_LEN + 8);
+        assert_eq!(offset_of!(RuleEvent, old), 4 + NAME_LEN + 12);
+        assert_eq!(offset_of!(QueryKey, client), 4);
+        assert_eq!(offset_of!(QueryKey, client_port), 8);
+        assert_eq!(offset_of!(QueryKey, txid), 10);
+        assert_eq!(offset_of!(QueryKey, qhash), 12);
+    }
+
+    #[test]
+    fn test_rule_value() {
+        for s in ["140.82.113.4", "0.0.0.0", "nxdomain", "pass"] {
+            assert_eq!(s.parse::<RuleValue>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "0.0.0.0".parse::<RuleValue>().unwrap().action(),
+            Some(Action::Sinkhole)
+        );
+        assert_eq!(
+            "140.82.113.4".parse::<RuleValue>().unwrap(),
+            RuleValue::new(Action::Rewrite, [140, 82, 113, 4])
+        );
+        assert!("block".parse::<RuleValue>().is_err());
+        assert_eq!(
+            RuleValue {
+                action: 9,
+                addr: [0; 4]
+            }
+            .action(),
+            None
+        );
+    }
+}
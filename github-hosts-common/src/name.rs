@@ -0,0 +1,323 @@
+//! DNS names in wire format, the way the eBPF program reads them from the
+//! question of a packet and looks them up in `GITHUB_HOSTS`.
+//!
+//! A hostname is encoded as its labels, each preceded by its length, followed
+//! by the empty root label and zero padded to [`NAME_LEN`]:
+//! `github.com` is `\x06github\x03com\x00`.
+
+use std::fmt;
+
+use crate::dns::MAX_LABELS;
+use crate::{RuleKey, NAME_LEN};
+
+// https://www.rfc-editor.org/rfc/rfc1035 2.3.4. Size limits
+pub const MAX_LABEL_LEN: usize = 63;
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Why a hostname can't be turned into a DNS wire-format map key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    Empty,
+    EmptyLabel,
+    /// label longer than 63 bytes
+    LabelTooLong(String),
+    /// encoded name longer than 255 bytes
+    NameTooLong(usize),
+    /// more labels than the program follows
+    TooManyLabels(usize),
+    Idna(String),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::Empty => write!(f, "empty hostname"),
+            EncodeError::EmptyLabel => write!(f, "empty label"),
+            EncodeError::LabelTooLong(label) => write!(
+                f,
+                "label `{}` is {} bytes long, the limit is {}",
+                label,
+                label.len(),
+                MAX_LABEL_LEN
+            ),
+            EncodeError::NameTooLong(len) => write!(
+                f,
+                "name is {} bytes long on the wire, the limit is {}",
+                len, MAX_NAME_LEN
+            ),
+            EncodeError::TooManyLabels(n) => write!(
+                f,
+                "name has {} labels, the program only matches names of up to {}",
+                n, MAX_LABELS
+            ),
+            EncodeError::Idna(host) => write!(f, "`{}` is not a valid IDNA name", host),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// Why a DNS wire-format name can't be turned back into a hostname.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// `\x` not followed by two hex digits, or odd-length hex
+    BadEscape(String),
+    /// a label runs past the end of the name
+    Truncated,
+    /// no root label
+    Unterminated,
+    /// a label with a dot in it or that isn't UTF-8
+    BadLabel(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadEscape(s) => write!(f, "invalid escape in `{}`", s),
+            DecodeError::Truncated => write!(f, "label runs past the end of the name"),
+            DecodeError::Unterminated => write!(f, "name has no root label"),
+            DecodeError::BadLabel(label) => write!(f, "invalid label `{}`", label),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A hostname as typed by a user, in the form rules are kept in: lowercase,
+/// unicode labels as punycode, without the trailing dot of a fully qualified
+/// name. It is what [`decode`] gives back for the key of the host.
+pub fn normalize(host: &str) -> String {
+    let host = host.trim_end_matches('.');
+    idna::domain_to_ascii(host).unwrap_or_else(|_| host.to_ascii_lowercase())
+}
+
+/// The wire-format name of a hostname, as in a `GITHUB_HOSTS` key.
+pub fn encode(host: &str) -> Result<[u8; NAME_LEN], EncodeError> {
+    // a fully qualified name and its relative form are the same key
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() {
+        return Err(EncodeError::Empty);
+    }
+    // lowercases ASCII and turns unicode labels into punycode ones
+    let host = idna::domain_to_ascii(host).map_err(|_| EncodeError::Idna(host.to_string()))?;
+
+    let labels = host.split('.').count();
+    if labels > MAX_LABELS {
+        return Err(EncodeError::TooManyLabels(labels));
+    }
+    let mut wire = vec![];
+    for label in host.split('.') {
+        if label.is_empty() {
+            return Err(EncodeError::EmptyLabel);
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(EncodeError::LabelTooLong(label.to_string()));
+        }
+        wire.push(label.len() as u8);
+        wire.extend(label.as_bytes());
+    }
+    wire.push(0);
+    if wire.len() > MAX_NAME_LEN {
+        return Err(EncodeError::NameTooLong(wire.len()));
+    }
+    let mut name = [0; NAME_LEN];
+    name[..wire.len()].copy_from_slice(&wire);
+    Ok(name)
+}
+
+/// Inverse of [`encode`]: the hostname of the name at the start of `name`,
+/// trailing zero padding ignored.
+pub fn decode(name: &[u8]) -> Result<String, DecodeError> {
+    let mut labels = vec![];
+    let mut i = 0;
+    loop {
+        let len = *name.get(i).ok_or(DecodeError::Unterminated)? as usize;
+        if len == 0 {
+            break;
+        }
+        let label = name.get(i + 1..i + 1 + len).ok_or(DecodeError::Truncated)?;
+        match std::str::from_utf8(label) {
+            Ok(label) if !label.contains('.') => labels.push(label),
+            _ => {
+                return Err(DecodeError::BadLabel(
+                    String::from_utf8_lossy(label).into_owned(),
+                ))
+            }
+        }
+        i += 1 + len;
+    }
+    Ok(labels.join("."))
+}
+
+/// Length of the name at the start of a map key, root label included.
+pub fn wire_len(key: &[u8]) -> usize {
+    key.iter()
+        .position(|b| *b == 0)
+        .map_or(key.len(), |i| i + 1)
+}
+
+/// `\x06github\x03com\x00`, the way DNS names are written in tests.
+pub fn escape(name: &[u8]) -> String {
+    let mut out = String::new();
+    let mut i = 0;
+    while i < name.len() {
+        let len = name[i] as usize;
+        out += &format!("\\x{:02x}", len);
+        let label = &name[i + 1..(i + 1 + len).min(name.len())];
+        out += &String::from_utf8_lossy(label);
+        i += 1 + len;
+    }
+    out
+}
+
+/// The bytes of a name written as by [`escape`], or as plain hex.
+pub fn unescape(s: &str) -> Result<Vec<u8>, DecodeError> {
+    let bad = || DecodeError::BadEscape(s.to_string());
+    let hex = |h: &str| u8::from_str_radix(h, 16).map_err(|_| bad());
+    if !s.contains("\\x") {
+        return s
+            .as_bytes()
+            .chunks(2)
+            .map(|h| match std::str::from_utf8(h) {
+                Ok(h) if h.len() == 2 => hex(h),
+                _ => Err(bad()),
+            })
+            .collect();
+    }
+    let mut out = vec![];
+    let mut rest = s;
+    while let Some(i) = rest.find("\\x") {
+        out.extend(&rest.as_bytes()[..i]);
+        out.push(hex(rest.get(i + 2..i + 4).ok_or_else(bad)?)?);
+        rest = &rest[i + 4..];
+    }
+    out.extend(rest.as_bytes());
+    Ok(out)
+}
+
+/// The hostname of a map key, or the escaped name and why it isn't one.
+pub fn show(name: &[u8]) -> String {
+    match decode(name) {
+        Ok(host) => host,
+        Err(e) => format!("<{}: {}>", escape(&name[..wire_len(name)]), e),
+    }
+}
+
+impl RuleKey {
+    /// The key of the rule for `host` that applies to the clients of `policy`.
+    pub fn from_host(policy: u32, host: &str) -> Result<Self, EncodeError> {
+        Ok(RuleKey {
+            policy,
+            name: encode(host)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // helper function for test
+    fn to_256(input: &[u8]) -> [u8; NAME_LEN] {
+        if input.len() >= NAME_LEN {
+            return input[0..NAME_LEN].try_into().unwrap();
+        }
+        let mut result = [0; NAME_LEN];
+        result[..input.len()].copy_from_slice(input);
+        result
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode("github.githubassets.com"),
+            Ok(to_256(b"\x06github\x0cgithubassets\x03com\x00"))
+        );
+        // trailing dot and case are normalised away
+        assert_eq!(encode("GitHub.com."), encode("github.com"));
+        // IDNA input is encoded as punycode, punycode input is kept as is
+        assert_eq!(
+            encode("例子.测试"),
+            Ok(to_256(b"\x0bxn--fsqu00a\x0bxn--0zwm56d\x00"))
+        );
+        assert_eq!(encode("xn--fsqu00a.xn--0zwm56d"), encode("例子.测试"));
+        assert_eq!(
+            RuleKey::from_host(2, "github.com"),
+            Ok(RuleKey {
+                policy: 2,
+                name: to_256(b"\x06github\x03com\x00"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_limits() {
+        assert_eq!(encode(""), Err(EncodeError::Empty));
+        assert_eq!(encode("."), Err(EncodeError::Empty));
+        assert_eq!(encode("github..com"), Err(EncodeError::EmptyLabel));
+
+        let label = "a".repeat(63);
+        assert!(encode(&format!("{}.com", label)).is_ok());
+        let label = "a".repeat(64);
+        assert_eq!(
+            encode(&format!("{}.com", label)),
+            Err(EncodeError::LabelTooLong(label))
+        );
+
+        assert!(encode("github.global.ssl.fastly.net").is_ok());
+        assert_eq!(
+            encode("github.global.ssl.fastly.net.lan"),
+            Err(EncodeError::TooManyLabels(6))
+        );
+
+        // 4 labels of 63 bytes: 4 * 64 + 1 = 257 bytes on the wire
+        let name = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(encode(&name), Err(EncodeError::NameTooLong(257)));
+        // 253 characters is the longest name that fits in 255 wire bytes
+        let name = format!("{}.{}", vec!["a".repeat(63); 3].join("."), "a".repeat(61));
+        assert_eq!(name.len(), 253);
+        assert!(encode(&name).is_ok());
+    }
+
+    #[test]
+    fn test_decode() {
+        let key = encode("github.com").unwrap();
+        assert_eq!(decode(&key).unwrap(), "github.com");
+        assert_eq!(escape(&key[..wire_len(&key)]), "\\x06github\\x03com\\x00");
+
+        let name = unescape("\\x06github\\x03com\\x00").unwrap();
+        assert_eq!(name, b"\x06github\x03com\x00");
+        assert_eq!(unescape("0667697468756203636f6d00").unwrap(), name);
+        assert_eq!(decode(&name).unwrap(), "github.com");
+
+        assert!(matches!(unescape("\\x6"), Err(DecodeError::BadEscape(_))));
+        assert!(matches!(unescape("abc"), Err(DecodeError::BadEscape(_))));
+        assert_eq!(decode(b"\x06github\x03com"), Err(DecodeError::Unterminated));
+        assert_eq!(decode(b"\x06git"), Err(DecodeError::Truncated));
+        assert!(matches!(
+            decode(b"\x03a.b\x00"),
+            Err(DecodeError::BadLabel(_))
+        ));
+        assert_eq!(
+            show(b"\x06git"),
+            "<\\x06git: label runs past the end of the name>"
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(b"\x06github\x03com\x00"), "\\x06github\\x03com\\x00");
+        // a length byte that happens to be printable
+        let name = [&[33u8][..], &[b'a'; 33], b"\x00"].concat();
+        assert_eq!(escape(&name), format!("\\x21{}\\x00", "a".repeat(33)));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("GitHub.com."), "github.com");
+        assert_eq!(normalize("例子.测试."), "xn--fsqu00a.xn--0zwm56d");
+        for host in ["GitHub.com.", "例子.测试"] {
+            assert_eq!(decode(&encode(host).unwrap()).unwrap(), normalize(host));
+        }
+    }
+}
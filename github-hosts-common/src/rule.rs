@@ -0,0 +1,82 @@
+//! Rules given on the command line, e.g. with `--extra-rule`.
+
+use std::str::FromStr;
+
+use crate::hosts::Window;
+use crate::name::normalize;
+use crate::{RuleValue, DEFAULT_POLICY};
+
+/// `[policy:]host=rule[@HH:MM-HH:MM]`, the rule being as accepted by
+/// [`RuleValue`]'s [`FromStr`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub policy: u32,
+    /// Normalised, see [`normalize`]
+    pub host: String,
+    pub rule: RuleValue,
+    /// From an `@HH:MM-HH:MM` suffix
+    pub window: Option<Window>,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, rule) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected `host=ip`, got `{}`", s))?;
+        let (rule, window) = match rule.split_once('@') {
+            Some((rule, window)) => (rule, Some(window.parse()?)),
+            None => (rule, None),
+        };
+        let (policy, host) = match host.split_once(':') {
+            Some((policy, host)) => (
+                policy
+                    .parse()
+                    .map_err(|_| format!("invalid policy `{}`", policy))?,
+                host,
+            ),
+            None => (DEFAULT_POLICY, host),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in `{}`", s));
+        }
+        Ok(Rule {
+            policy,
+            host: normalize(host),
+            rule: rule.parse()?,
+            window,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "2:GitHub.com=140.82.113.4".parse(),
+            Ok(Rule {
+                policy: 2,
+                host: "github.com".into(),
+                rule: "140.82.113.4".parse().unwrap(),
+                window: None,
+            })
+        );
+        assert_eq!(
+            "github.com=140.82.113.4@22:30-06:00"
+                .parse::<Rule>()
+                .map(|r| r.window.map(|w| w.to_string())),
+            Ok(Some("22:30-06:00".to_string()))
+        );
+        assert!("github.com=140.82.113.4@9-18".parse::<Rule>().is_err());
+        assert_eq!(
+            "github.com=nxdomain".parse::<Rule>().map(|r| r.policy),
+            Ok(DEFAULT_POLICY)
+        );
+        assert!("ci:github.com=140.82.113.4".parse::<Rule>().is_err());
+        assert!("2:=140.82.113.4".parse::<Rule>().is_err());
+    }
+}
@@ -2,17 +2,29 @@
 #![no_main]
 
 use aya_bpf::{
-    bindings::xdp_action,
-    macros::xdp,
-    macros::map,
-    maps::HashMap,
-    programs::XdpContext,
+    bindings::{__sk_buff, xdp_action, BPF_F_MARK_MANGLED_0, BPF_F_NO_PREALLOC, TC_ACT_PIPE},
+    cty::c_void,
+    helpers::{bpf_ktime_get_ns, bpf_l4_csum_replace, bpf_skb_store_bytes, bpf_xdp_adjust_tail},
+    macros::{classifier, map, xdp},
+    maps::{
+        lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, LruPerCpuHashMap, PerCpuArray,
+        PerfEventArray,
+    },
+    programs::{TcContext, XdpContext},
+    BpfContext,
 };
 use aya_log_ebpf::{info, trace, debug};
 
 mod bindings;
 use bindings::{ethhdr, iphdr, udphdr};
 use core::mem;
+use github_hosts_common::dns::{self, Bytes};
+use github_hosts_common::{
+    Action, QueryKey, RttHistogram, RuleEvent, RuleKey, RuleValue, CONFIG_FLAGS, CONFIG_MAX,
+    DEFAULT_POLICY, FLAG_AUTHORITATIVE, FLAG_BYPASS, FLAG_DRY_RUN, FLAG_RTT, FLAG_VERIFY, RTT_BUCKETS,
+    STAT_ANSWERED, STAT_BLOCKED, STAT_DNS_RESPONSES, STAT_DRY_RUN, STAT_MATCHED, STAT_MAX,
+    STAT_PACKETS, STAT_QUERIES, STAT_REWRITTEN, STAT_UNSOLICITED,
+};
 
 // https://www.ietf.org/rfc/rfc1035.txt 4.1.1
 #[repr(C)]
@@ -45,11 +57,137 @@ const UDP_HDR_LEN_ALL: usize = ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN;
 const DNS_HDR_LEN_ALL: usize = ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN+DNS_HDR_LEN;
 
 #[map(name = "GITHUB_HOSTS")]
-static mut GITHUB_HOSTS: HashMap<[u8;256], [u8;4]> =
-    HashMap::<[u8;256], [u8;4]>::with_max_entries(256, 0);
+static mut GITHUB_HOSTS: HashMap<RuleKey, RuleValue> =
+    HashMap::<RuleKey, RuleValue>::pinned(256, 0);
+
+/// Client subnets, in network byte order, to their policy
+#[map(name = "CLIENTS")]
+static mut CLIENTS: LpmTrie<u32, u32> = LpmTrie::<u32, u32>::pinned(1024, BPF_F_NO_PREALLOC);
+
+#[map(name = "STATS")]
+static mut STATS: PerCpuArray<u64> = PerCpuArray::<u64>::pinned(STAT_MAX, 0);
+
+#[map(name = "CONFIG")]
+static mut CONFIG: Array<u32> = Array::<u32>::pinned(CONFIG_MAX, 0);
+
+/// Outstanding queries, to when they were sent (ns since boot)
+#[map(name = "QUERIES")]
+static mut QUERIES: LruHashMap<QueryKey, u64> = LruHashMap::<QueryKey, u64>::pinned(4096, 0);
+
+/// How long a query is waited on, past which a response to it is taken as
+/// unsolicited: resolvers retry well before
+const QUERY_TIMEOUT_NS: u64 = 5_000_000_000;
+
+/// Response time histograms, by resolver address
+#[map(name = "RTT")]
+static mut RTT: LruPerCpuHashMap<u32, RttHistogram> =
+    LruPerCpuHashMap::<u32, RttHistogram>::pinned(256, 0);
+
+/// What would have been done to a response, in dry-run mode
+#[map(name = "EVENTS")]
+static mut EVENTS: PerfEventArray<RuleEvent> = PerfEventArray::<RuleEvent>::new(0);
+
+/// Where an event is put together, too large for the stack next to the
+/// question
+#[map(name = "EVENT_SCRATCH")]
+static mut EVENT_SCRATCH: PerCpuArray<RuleEvent> = PerCpuArray::<RuleEvent>::with_max_entries(1, 0);
 
+/// Is any of the bits of `flag` set?
 #[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
+fn flag(flag: u32) -> bool {
+    match unsafe { CONFIG.get(CONFIG_FLAGS) } {
+        Some(flags) => flags & flag != 0,
+        None => false,
+    }
+}
+
+#[inline(always)]
+fn count(index: u32) {
+    if let Some(counter) = unsafe { STATS.get_ptr_mut(index) } {
+        unsafe { *counter += 1 };
+    }
+}
+
+/// What the parser needs from a program context, so that the XDP and TC
+/// programs share it.
+trait Packet: BpfContext + Sized {
+    /// Let the packet through unchanged.
+    const PASS: u32;
+    /// See [`Bytes::SHRINK`]
+    const SHRINK: bool = true;
+    fn data(&self) -> usize;
+    fn data_end(&self) -> usize;
+
+    /// See [`Bytes::replace`]
+    #[inline(always)]
+    fn replace<const N: usize>(&self, offset: usize, _old: [u8; N], value: [u8; N]) -> Option<()> {
+        unsafe {
+            *ptr_at_mut::<Self, [u8; 2]>(self, dns::UDP_OFF + 6)? = [0; 2];
+            *ptr_at_mut::<Self, [u8; N]>(self, offset)? = value;
+        }
+        Some(())
+    }
+}
+
+impl Packet for XdpContext {
+    const PASS: u32 = xdp_action::XDP_PASS;
+    fn data(&self) -> usize {
+        XdpContext::data(self)
+    }
+    fn data_end(&self) -> usize {
+        XdpContext::data_end(self)
+    }
+}
+
+impl Packet for TcContext {
+    const PASS: u32 = TC_ACT_PIPE as u32;
+    const SHRINK: bool = false;
+    fn data(&self) -> usize {
+        TcContext::data(self)
+    }
+    fn data_end(&self) -> usize {
+        TcContext::data_end(self)
+    }
+
+    /// A locally generated skb is usually CHECKSUM_PARTIAL, its checksum
+    /// field only a seed for the one the NIC computes: clearing it would
+    /// get a wrong checksum sent. The kernel updates it the way the skb
+    /// needs, a byte at a time as the bytes need not be 16 bit aligned.
+    #[inline(always)]
+    fn replace<const N: usize>(&self, offset: usize, old: [u8; N], value: [u8; N]) -> Option<()> {
+        let skb = self.as_ptr() as *mut __sk_buff;
+        let ret = unsafe {
+            bpf_skb_store_bytes(skb, offset as u32, value.as_ptr() as *const c_void, N as u32, 0)
+        };
+        if ret != 0 {
+            return None;
+        }
+        for i in 0..N {
+            // the checksum sums 16 bit words from the UDP header on
+            let (from, to) = if (offset + i - dns::UDP_OFF) % 2 == 0 {
+                ([old[i], 0], [value[i], 0])
+            } else {
+                ([0, old[i]], [0, value[i]])
+            };
+            let ret = unsafe {
+                bpf_l4_csum_replace(
+                    skb,
+                    (dns::UDP_OFF + 6) as u32,
+                    u16::from_ne_bytes(from) as u64,
+                    u16::from_ne_bytes(to) as u64,
+                    BPF_F_MARK_MANGLED_0 as u64 | 2,
+                )
+            };
+            if ret != 0 {
+                return None;
+            }
+        }
+        Some(())
+    }
+}
+
+#[inline(always)]
+fn ptr_at<C: Packet, T>(ctx: &C, offset: usize) -> Option<*const T> {
     let start = ctx.data();
     let end = ctx.data_end();
     let len = mem::size_of::<T>();
@@ -62,171 +200,387 @@ fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
 }
 
 #[inline(always)]
-fn ptr_at_mut<T>(ctx: &XdpContext, offset: usize) -> Option<*mut T> {
-    let ptr = ptr_at::<T>(ctx, offset)?;
+fn ptr_at_mut<C: Packet, T>(ctx: &C, offset: usize) -> Option<*mut T> {
+    let ptr = ptr_at::<C, T>(ctx, offset)?;
     Some(ptr as *mut T)
 }
 
+/// A packet as seen by the DNS core shared with userspace.
+struct Pkt<'a, C>(&'a C);
+
+impl<C: Packet> Bytes for Pkt<'_, C> {
+    #[inline(always)]
+    fn load<const N: usize>(&self, offset: usize) -> Option<[u8; N]> {
+        Some(unsafe { *ptr_at::<C, [u8; N]>(self.0, offset)? })
+    }
+
+    #[inline(always)]
+    fn store<const N: usize>(&mut self, offset: usize, value: [u8; N]) -> Option<()> {
+        unsafe { *ptr_at_mut::<C, [u8; N]>(self.0, offset)? = value };
+        Some(())
+    }
+
+    const SHRINK: bool = C::SHRINK;
+
+    #[inline(always)]
+    fn replace<const N: usize>(&mut self, offset: usize, old: [u8; N], value: [u8; N]) -> Option<()> {
+        self.0.replace(offset, old, value)
+    }
+}
+
+/// The rule for the question in `key` that applies to `client`: the one of
+/// its policy if there is one, or else the default one.
+#[inline(always)]
+fn lookup(key: &mut RuleKey, client: u32) -> Option<RuleValue> {
+    if let Some(policy) = unsafe { CLIENTS.get(&Key::new(32, client)) } {
+        key.policy = *policy;
+        if let Some(rule) = unsafe { GITHUB_HOSTS.get(key) } {
+            return Some(*rule);
+        }
+        key.policy = DEFAULT_POLICY;
+    }
+    unsafe { GITHUB_HOSTS.get(key) }.copied()
+}
+
+const FNV_OFFSET: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+/// Key of the exchange a query or response is part of, from the client's
+/// side: a query goes out to the resolver, its response comes back from it.
+#[inline(always)]
+fn query_key<C: Packet>(ctx: &C, outgoing: bool) -> Option<QueryKey> {
+    let eth = ptr_at::<C, ethhdr>(ctx, 0)?;
+    if unsafe { u16::from_be((*eth).h_proto) } != ETH_P_IP {
+        return None;
+    }
+    let ip = ptr_at::<C, iphdr>(ctx, ETH_HDR_LEN)?;
+    if unsafe { (*ip).protocol } != IPPROTO_UDP {
+        return None;
+    }
+    let udp = ptr_at::<C, udphdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN)?;
+    let dns_hdr = ptr_at::<C, dnshdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN)?;
+    let (resolver, client, server_port, client_port) = unsafe {
+        let addrs = (*ip).__bindgen_anon_1.addrs;
+        if outgoing {
+            (addrs.daddr, addrs.saddr, (*udp).dest, (*udp).source)
+        } else {
+            (addrs.saddr, addrs.daddr, (*udp).source, (*udp).dest)
+        }
+    };
+    let response = u16::from_be(unsafe { (*dns_hdr).flags }) & DNS_FLAG_QR != 0;
+    if u16::from_be(server_port) != 53 || response == outgoing {
+        return None;
+    }
+    if u16::from_be_bytes(unsafe { (*dns_hdr).qcount }) != 1 {
+        return None;
+    }
+    let q_len = dns::name_len(&Pkt(ctx), DNS_HDR_LEN_ALL)?;
+    let query = dns::question_key(&Pkt(ctx), q_len)?;
+    let mut qhash = FNV_OFFSET;
+    for i in 0..255 {
+        if i >= q_len {
+            break;
+        }
+        qhash = (qhash ^ query.name[i] as u32).wrapping_mul(FNV_PRIME);
+    }
+    Some(QueryKey {
+        resolver,
+        client,
+        client_port,
+        txid: unsafe { (*dns_hdr).trans_id },
+        qhash,
+    })
+}
+
+/// Count a response from `resolver` to a query sent at `sent`.
 #[inline(always)]
-fn parse_dns_host(ctx: &XdpContext, offset: usize) -> Option<usize>{
-    let mut j = offset;
-    let a_num = unsafe { *(ptr_at::<u16>(&ctx, j)?)  };
-    let a_num = u16::from_be(a_num);
-    if a_num & 0xc000 == 0xc000 {
-        debug!(ctx, "compressed...");
-        return Some(2);
-    } 
-    // 0..5: support a.b.c.d
-    for i in 0..5 {
-        let a_num = unsafe { *(ptr_at::<u8>(&ctx, j)?)  };
-        j += 1;
-        if a_num == 0 {
+fn record_rtt(resolver: u32, sent: u64) {
+    let mut us = (unsafe { bpf_ktime_get_ns() } - sent) / 1000;
+    let mut bucket = 0;
+    for _ in 0..RTT_BUCKETS - 1 {
+        if us == 0 {
             break;
         }
-        debug!(ctx, "a_num: 0x{:x} i: {}", a_num, i);
-        j += a_num as usize;
+        us >>= 1;
+        bucket += 1;
+    }
+    match unsafe { RTT.get_ptr_mut(&resolver) } {
+        Some(histogram) => unsafe { (*histogram).buckets[bucket] += 1 },
+        None => {
+            let mut histogram = RttHistogram { buckets: [0; RTT_BUCKETS] };
+            histogram.buckets[bucket] = 1;
+            let _ = unsafe { RTT.insert(&resolver, &histogram, 0) };
+        }
     }
-    return Some(j - offset);
 }
 
+/// Record the DNS queries leaving the interface, for `FLAG_VERIFY` and
+/// `FLAG_RTT`.
+#[classifier(name="github_hosts_queries")]
+pub fn github_hosts_queries(ctx: TcContext) -> i32 {
+    let _ = ctx.pull_data(ctx.len().min(TC_PULL_LEN));
+    if let Some(key) = query_key(&ctx, true) {
+        let now = unsafe { bpf_ktime_get_ns() };
+        if unsafe { QUERIES.insert(&key, &now, 0) }.is_ok() {
+            count(STAT_QUERIES);
+        }
+    }
+    TC_ACT_PIPE as i32
+}
 
+const DNS_FLAG_QR: u16 = 0x8000;
+const DNS_OPCODE_MASK: u16 = 0x7800;
+const DNS_FLAG_AA: u16 = 0x0400;
+const DNS_FLAG_RD: u16 = 0x0100;
+const DNS_FLAG_RA: u16 = 0x0080;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+const CLASS_IN: [u8;2] = 1u16.to_be_bytes();
+/// TTL of the answers made up in authoritative mode
+const ANSWER_TTL: u32 = 60;
+/// Answer record: pointer to the question name, type, class, ttl, rdlength
+/// and the address
+const ANSWER_LEN: usize = 16;
+
+/// In authoritative mode, answer an A query that has a rule right away,
+/// sending the response back out of the interface it came in on. Queries of
+/// any type for an NXDOMAIN host get an NXDOMAIN answer.
+fn try_answer(ctx: &XdpContext) -> Option<u32> {
+    let eth = ptr_at::<XdpContext, ethhdr>(ctx, 0)?;
+    if unsafe { u16::from_be((*eth).h_proto) } != ETH_P_IP {
+        return None;
+    }
+    let ip = ptr_at::<XdpContext, iphdr>(ctx, ETH_HDR_LEN)?;
+    if unsafe { (*ip).protocol } != IPPROTO_UDP {
+        return None;
+    }
+    let udp = ptr_at::<XdpContext, udphdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN)?;
+    if unsafe { u16::from_be((*udp).dest) } != 53 {
+        return None;
+    }
+    let dns_hdr = ptr_at::<XdpContext, dnshdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN)?;
+    let flags = u16::from_be(unsafe { (*dns_hdr).flags });
+    if flags & (DNS_FLAG_QR | DNS_OPCODE_MASK) != 0
+        || u16::from_be_bytes(unsafe { (*dns_hdr).qcount }) != 1
+        || u16::from_be_bytes(unsafe { (*dns_hdr).acount }) != 0
+    {
+        return None;
+    }
+    let q_len = dns::name_len(&Pkt(ctx), DNS_HDR_LEN_ALL)?;
+    let q_type = unsafe { *ptr_at::<XdpContext, [u8;2]>(ctx, DNS_HDR_LEN_ALL + q_len)? };
+    let q_class = unsafe { *ptr_at::<XdpContext, [u8;2]>(ctx, DNS_HDR_LEN_ALL + q_len + 2)? };
+    if q_class != CLASS_IN {
+        return None;
+    }
+    let mut query = dns::question_key(&Pkt(ctx), q_len)?;
+    let client = unsafe { (*ip).__bindgen_anon_1.addrs.saddr };
+    let rule = lookup(&mut query, client)?;
+    let nxdomain = rule.action == Action::Nxdomain as u32;
+    if rule.action == Action::Pass as u32 || (q_type != A && !nxdomain) {
+        return None;
+    }
+    count(STAT_MATCHED);
+
+    // drop whatever follows the question, e.g. an EDNS OPT record, and make
+    // room for the answer
+    let answer_at = DNS_HDR_LEN_ALL + q_len + 4;
+    let answer_len = if nxdomain { 0 } else { ANSWER_LEN };
+    let delta = (answer_at + answer_len) as i32 - (ctx.data_end() - ctx.data()) as i32;
+    if unsafe { bpf_xdp_adjust_tail(ctx.ctx, delta) } != 0 {
+        return None;
+    }
+    // the packet changed, every pointer has to be checked again
+    let eth = ptr_at_mut::<XdpContext, ethhdr>(ctx, 0)?;
+    let ip = ptr_at_mut::<XdpContext, iphdr>(ctx, ETH_HDR_LEN)?;
+    let udp = ptr_at_mut::<XdpContext, udphdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN)?;
+    let dns_hdr = ptr_at_mut::<XdpContext, dnshdr>(ctx, ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN)?;
+    let answer = if nxdomain {
+        None
+    } else {
+        Some(ptr_at_mut::<XdpContext, [u8; ANSWER_LEN]>(ctx, answer_at)?)
+    };
+    unsafe {
+        let mac = (*eth).h_dest;
+        (*eth).h_dest = (*eth).h_source;
+        (*eth).h_source = mac;
+
+        let addrs = &mut (*ip).__bindgen_anon_1.addrs;
+        let saddr = addrs.saddr;
+        addrs.saddr = addrs.daddr;
+        addrs.daddr = saddr;
+        (*ip).tot_len = ((answer_at + answer_len - ETH_HDR_LEN) as u16).to_be();
+        (*ip).ttl = 64;
+
+        let port = (*udp).source;
+        (*udp).source = (*udp).dest;
+        (*udp).dest = port;
+        (*udp).len = ((answer_at + answer_len - ETH_HDR_LEN - IP_HDR_LEN) as u16).to_be();
+        (*udp).check = 0;
+
+        let rd = u16::from_be((*dns_hdr).flags) & DNS_FLAG_RD;
+        let rcode = if nxdomain { DNS_RCODE_NXDOMAIN } else { 0 };
+        (*dns_hdr).flags = (DNS_FLAG_QR | DNS_FLAG_AA | rd | DNS_FLAG_RA | rcode).to_be();
+        (*dns_hdr).acount = (answer.is_some() as u16).to_be_bytes();
+        (*dns_hdr).nscount = [0, 0];
+        (*dns_hdr).arcount = [0, 0];
+
+        if let Some(answer) = answer {
+            let ttl = ANSWER_TTL.to_be_bytes();
+            let addr = rule.addr;
+            *answer = [
+                0xc0, DNS_HDR_LEN as u8, A[0], A[1], CLASS_IN[0], CLASS_IN[1],
+                ttl[0], ttl[1], ttl[2], ttl[3], 0, 4, addr[0], addr[1], addr[2], addr[3],
+            ];
+        }
+    }
+    dns::set_ip_checksum(&mut Pkt(ctx))?;
+    count(STAT_ANSWERED);
+    if rule.action != Action::Rewrite as u32 {
+        count(STAT_BLOCKED);
+    }
+    Some(xdp_action::XDP_TX)
+}
+
+/// Report that `rule` matched the response to `client` for `query`, whose
+/// first A record was `old`.
+#[inline(always)]
+fn report<C: Packet>(ctx: &C, query: &RuleKey, rule: RuleValue, client: u32, old: [u8; 4]) {
+    count(STAT_DRY_RUN);
+    if let Some(event) = unsafe { EVENT_SCRATCH.get_ptr_mut(0) } {
+        unsafe {
+            (*event).key = *query;
+            (*event).rule = rule;
+            (*event).client = client;
+            (*event).old = old;
+            EVENTS.output(ctx, &*event, 0);
+        }
+    }
+}
 
 #[xdp(name="github_hosts")]
 pub fn github_hosts(ctx: XdpContext) -> u32 {
-    match try_github_hosts(ctx) {
+    if flag(FLAG_BYPASS) {
+        return xdp_action::XDP_PASS;
+    }
+    // in dry-run mode the queries go to the resolver, and its responses are
+    // reported instead
+    if flag(FLAG_AUTHORITATIVE) && !flag(FLAG_DRY_RUN) {
+        if let Some(action) = try_answer(&ctx) {
+            return action;
+        }
+    }
+    match try_github_hosts(&ctx) {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
     }
 }
 
-fn try_github_hosts(ctx: XdpContext) -> Result<u32, u32> {
-    trace!(&ctx, "received a packet");
-    let eth = ptr_at::<ethhdr>(&ctx, 0).ok_or(xdp_action::XDP_PASS)?;
-    if unsafe { u16::from_be((*eth).h_proto) } != ETH_P_IP {
-        return Ok(xdp_action::XDP_PASS);
+/// Largest DNS response made linear for the TC program, more than the
+/// parser ever looks at.
+const TC_PULL_LEN: u32 = 1500;
+
+#[classifier(name="github_hosts_tc")]
+pub fn github_hosts_tc(ctx: TcContext) -> i32 {
+    if flag(FLAG_BYPASS) {
+        return TC_ACT_PIPE as i32;
     }
-    let ip = ptr_at::<iphdr>(&ctx, ETH_HDR_LEN).ok_or(xdp_action::XDP_PASS)?;
-    if unsafe { (*ip).protocol } != IPPROTO_UDP {
-        return Ok(xdp_action::XDP_PASS);
-    }
-    trace!(&ctx, "received a UDP packet");
-    let udp = ptr_at_mut::<udphdr>(&ctx, ETH_HDR_LEN + IP_HDR_LEN).ok_or(xdp_action::XDP_PASS)?;
-    unsafe { (*udp).check = 0 };
-    let destination_port = unsafe { u16::from_be((*udp).dest) };
-    let src_port = unsafe { u16::from_be((*udp).source) };
-    if src_port != 53 {
-        return Ok(xdp_action::XDP_PASS);
-    }
-    let udp_len = unsafe { u16::from_be((*udp).len) };
-    let ip_dest = unsafe {
-        (*ip).__bindgen_anon_1.addrs.daddr
-    };
-    let ip_src = unsafe {
-        (*ip).__bindgen_anon_1.addrs.saddr
-    };
-    trace!(&ctx, "ip_src: 0x{:x}, ip_dst: 0x{:x}", ip_src, ip_dest);
-    trace!(&ctx, "src_port: {}, dest_port: {}", src_port, destination_port);
-    let mut data_len:usize = ctx.data_end() - ctx.data();
-    trace!(&ctx, "data len: {}", data_len);
-    trace!(&ctx, "udp_len: {}", udp_len);
-    let dns_hdr = ptr_at_mut::<dnshdr>(&ctx, ETH_HDR_LEN + IP_HDR_LEN + UDP_HDR_LEN).ok_or(xdp_action::XDP_PASS)?;
-    let qcount = u16::from_be_bytes(unsafe { (*dns_hdr).qcount }); 
-    let acount = u16::from_be_bytes(unsafe { (*dns_hdr).acount }); 
-    if qcount != 1 {
-        info!(&ctx, "only support single question, pass");
-        return Ok(xdp_action::XDP_PASS);
-    }
-    if acount == 0 {
-        info!(&ctx, "no answer rrs, pass");
-        return Ok(xdp_action::XDP_PASS);
-    }
-    debug!(&ctx, "answer number: {}", acount);
-    let mut j = 0;
-    let mut num = 0u8;
-    let mut q_len = 0;
-    let mut query = [0u8;256];
-    let mut qi = 0;
-
-    let q_len = parse_dns_host(&ctx, DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?; 
-    debug!(&ctx, "q_len: {}", q_len);
-    j += q_len;
-    let buf_ss = unsafe { ptr_at::<u8>(&ctx, DNS_HDR_LEN_ALL ).ok_or(xdp_action::XDP_PASS)? };
-    let buf_ee = unsafe { ptr_at::<u8>(&ctx, DNS_HDR_LEN_ALL+q_len ).ok_or(xdp_action::XDP_PASS)? };
-    // TODO: size limit
-    // https://www.rfc-editor.org/rfc/rfc1035 2.3.4. Size limits
-    let mut i = 0usize;
-    for _ in 0..255 {
-        query[i] = unsafe { *(ptr_at::<u8>(&ctx, DNS_HDR_LEN_ALL+i ).ok_or(xdp_action::XDP_PASS)?) };
-        i += 1;
-        if ctx.data() + DNS_HDR_LEN_ALL + i >= ctx.data_end() {
-            break;
-        }
-        if unsafe { buf_ss.offset(i as _) } >= buf_ee {
-            break;
+    // direct packet access only reaches the linear part of the skb
+    let _ = ctx.pull_data(ctx.len().min(TC_PULL_LEN));
+    match try_github_hosts(&ctx) {
+        Ok(ret) | Err(ret) => ret as i32,
+    }
+}
+
+fn try_github_hosts<C: Packet>(ctx: &C) -> Result<u32, u32> {
+    trace!(ctx, "received a packet");
+    count(STAT_PACKETS);
+    let mut pkt = Pkt(ctx);
+    let udp = dns::udp(&pkt).ok_or(C::PASS)?;
+    trace!(ctx, "received a UDP packet");
+    if udp.source != 53 {
+        return Ok(C::PASS);
+    }
+    count(STAT_DNS_RESPONSES);
+    // whether this answers a query we recorded
+    let mut solicited = false;
+    if flag(FLAG_VERIFY | FLAG_RTT) {
+        if let Some(key) = query_key(ctx, false) {
+            if let Some(sent) = unsafe { QUERIES.get(&key) } {
+                let sent = *sent;
+                let now = unsafe { bpf_ktime_get_ns() };
+                if now.wrapping_sub(sent) <= QUERY_TIMEOUT_NS {
+                    if flag(FLAG_RTT) {
+                        record_rtt(key.resolver, sent);
+                    }
+                    solicited = true;
+                }
+                let _ = unsafe { QUERIES.remove(&key) };
+            }
         }
     }
-    let ip = match unsafe { GITHUB_HOSTS.get(&query) } {
-        Some(backends) => {
-            info!(&ctx, "found github hosts");
-            backends
+    trace!(ctx, "ip_src: 0x{:x}, ip_dst: 0x{:x}", udp.saddr, udp.daddr);
+    trace!(ctx, "src_port: {}, dest_port: {}", udp.source, udp.dest);
+    let header = dns::header(&pkt).ok_or(C::PASS)?;
+    if header.qcount != 1 {
+        info!(ctx, "only support single question, pass");
+        return Ok(C::PASS);
+    }
+    debug!(ctx, "answer number: {}", header.acount);
+    let q_len = dns::name_len(&pkt, DNS_HDR_LEN_ALL).ok_or(C::PASS)?;
+    debug!(ctx, "q_len: {}", q_len);
+    let mut query = dns::question_key(&pkt, q_len).ok_or(C::PASS)?;
+    let rule = match lookup(&mut query, udp.daddr) {
+        Some(rule) => {
+            info!(ctx, "found github hosts, policy {}", query.policy);
+            count(STAT_MATCHED);
+            rule
         }
         None => {
-            info!(&ctx, "not github hosts");
-            return Ok(xdp_action::XDP_PASS);
+            info!(ctx, "not github hosts");
+            return Ok(C::PASS);
         }
     };
-    j += 4;
-    let mut found_a = false;
-    // parse DNS answers
-    // TODO: only support 20 answers now
-    for i in 0..20 {
-        let a_len = parse_dns_host(&ctx, (j as usize) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?;
-        info!(&ctx, "a_len: {}", a_len);
-        // need this or raise error: R1 min value is negative, either use unsigned index or do a if (index >=0) check.
-        if a_len > 255 {
-            return Ok(xdp_action::XDP_PASS);
-        }
-        j += a_len;
-       let a_type = unsafe { *(ptr_at_mut::<[u8;2]>(&ctx, (j as usize) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?) }; 
-       info!(&ctx, "a_type: {}", a_type[1]);
-        if a_type == A {
-            info!(&ctx, "found A record answer");
-            found_a = true;
-            break;
+    // off-path spoofed responses answer no query we sent
+    if flag(FLAG_VERIFY) && !solicited {
+        info!(ctx, "no matching query, pass");
+        count(STAT_UNSOLICITED);
+        return Ok(C::PASS);
+    }
+    if rule.action == Action::Pass as u32 {
+        info!(ctx, "pass rule");
+        return Ok(C::PASS);
+    }
+    let dry_run = flag(FLAG_DRY_RUN);
+    if rule.action == Action::Nxdomain as u32 {
+        if dry_run {
+            report(ctx, &query, rule, udp.daddr, [0; 4]);
+            return Ok(C::PASS);
+        }
+        dns::nxdomain(&mut pkt, q_len).ok_or(C::PASS)?;
+        info!(ctx, "answered NXDOMAIN");
+        count(STAT_BLOCKED);
+        return Ok(C::PASS);
+    }
+    if header.acount == 0 {
+        info!(ctx, "no answer rrs, pass");
+        return Ok(C::PASS);
+    }
+    let old = match dns::rewrite_a(&mut pkt, q_len, header.acount, rule.addr, dry_run) {
+        Some(old) => old,
+        None => {
+            info!(ctx, "no A record answer, pass");
+            return Ok(C::PASS);
         }
-        if a_type != A {
-            info!(&ctx, "not A record answer, skip {}", a_type[1]);
-        }
-        j += 2; // a_type
-        j += 2; // a_class
-        j += 4; // a_ttl
-        let a_data_len = u16::from_be_bytes(unsafe { *(ptr_at_mut::<[u8;2]>(&ctx, (j as usize) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?) }); 
-        info!(&ctx, "a_data_len: 0x{:x}", a_data_len);
-        j += 2; // a_data_length
-        // need this or raise error: math between pkt pointer and register with unbounded min value is not allowed
-        if a_data_len > 255 {
-            return Ok(xdp_action::XDP_PASS);
-        }
-        j += a_data_len as usize;
-    }
-
-    if !found_a {
-        info!(&ctx, "no A record answer, pass");
-        return Ok(xdp_action::XDP_PASS);
-    }
-
-    j += 10;
-    let ip0 =  ptr_at_mut::<u8>(&ctx, (j as usize +0) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?;
-    let ip1 =  ptr_at_mut::<u8>(&ctx, (j as usize +1) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?;
-    let ip2 =  ptr_at_mut::<u8>(&ctx, (j as usize +2) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?;
-    let ip3 =  ptr_at_mut::<u8>(&ctx, (j as usize +3) + DNS_HDR_LEN_ALL).ok_or(xdp_action::XDP_PASS)?;
-    info!(&ctx, "old ip: {}.{}.{}.{}", unsafe{*ip0}, unsafe{*ip1}, unsafe{*ip2}, unsafe{*ip3});
-    unsafe { *ip0 = ip[0] }
-    unsafe { *ip1 = ip[1] }
-    unsafe { *ip2 = ip[2] }
-    unsafe { *ip3 = ip[3] }
-    info!(&ctx, "new ip: {}.{}.{}.{}", unsafe{*ip0}, unsafe{*ip1}, unsafe{*ip2}, unsafe{*ip3});
-    return Ok(xdp_action::XDP_PASS);
+    };
+    info!(ctx, "old ip: {}.{}.{}.{}", old[0], old[1], old[2], old[3]);
+    if dry_run {
+        report(ctx, &query, rule, udp.daddr, old);
+    } else if rule.action == Action::Sinkhole as u32 {
+        count(STAT_BLOCKED);
+    } else {
+        info!(ctx, "new ip: {}.{}.{}.{}", rule.addr[0], rule.addr[1], rule.addr[2], rule.addr[3]);
+        count(STAT_REWRITTEN);
+    }
+    Ok(C::PASS)
 }
 
 #[panic_handler]
@@ -0,0 +1,218 @@
+//! Attaching the rewrite program to interfaces, as XDP or as a TC classifier.
+//! Either way one program, and so one `GITHUB_HOSTS` map, is shared by every
+//! interface.
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+use aya::programs::{
+    tc::{self, SchedClassifierLinkId},
+    xdp::XdpLinkId,
+    ProgramFd, SchedClassifier, TcAttachType, Xdp, XdpFlags,
+};
+use aya::Bpf;
+use log::{info, warn};
+
+use crate::pin;
+
+/// Where the program hooks into the network stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Hook {
+    Xdp,
+    /// TC clsact, sees traffic on virtual devices XDP doesn't, coexists with
+    /// other XDP programs and can see egress traffic
+    Tc,
+}
+
+/// Which side of the interface the TC classifier is attached to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Direction {
+    Ingress,
+    /// Answers sent by a resolver on this host, to local apps over `lo` or to
+    /// other machines
+    Egress,
+}
+
+impl Direction {
+    fn attach_type(self) -> TcAttachType {
+        match self {
+            Direction::Ingress => TcAttachType::Ingress,
+            Direction::Egress => TcAttachType::Egress,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum XdpMode {
+    /// In the driver, fastest, needs driver support
+    Native,
+    /// Generic XDP, works on any interface
+    Skb,
+    /// On the NIC. Not supported: the program would have to be loaded for
+    /// the device it is offloaded to
+    Offload,
+    Auto,
+}
+
+impl XdpMode {
+    /// The attach flags to try, in order, with the name of the mode.
+    fn flags(self) -> Vec<(&'static str, XdpFlags)> {
+        let native = ("native", XdpFlags::DRV_MODE);
+        let skb = ("skb", XdpFlags::SKB_MODE);
+        match self {
+            XdpMode::Native => vec![native],
+            XdpMode::Skb => vec![skb],
+            XdpMode::Offload => vec![("offload", XdpFlags::HW_MODE)],
+            XdpMode::Auto => vec![native, skb],
+        }
+    }
+}
+
+/// One of the attachments to an interface.
+#[derive(Debug)]
+pub enum Link {
+    Xdp(XdpLinkId),
+    Tc(SchedClassifierLinkId),
+    /// Of the query tracking classifier
+    Queries(SchedClassifierLinkId),
+    /// Kept by a link pinned in bpffs rather than by this process
+    Pinned,
+}
+
+pub struct Attacher {
+    pub hook: Hook,
+    pub xdp_mode: XdpMode,
+    pub tc_directions: Vec<Direction>,
+    /// Also attach the classifier recording outgoing queries
+    pub track_queries: bool,
+    /// Attach through links pinned in `bpffs`, which outlive the process
+    pub pin: bool,
+    pub bpffs: PathBuf,
+}
+
+fn xdp(bpf: &mut Bpf) -> Result<&mut Xdp, anyhow::Error> {
+    Ok(bpf.program_mut("github_hosts").unwrap().try_into()?)
+}
+
+fn classifier(bpf: &mut Bpf) -> Result<&mut SchedClassifier, anyhow::Error> {
+    Ok(bpf.program_mut("github_hosts_tc").unwrap().try_into()?)
+}
+
+fn queries(bpf: &mut Bpf) -> Result<&mut SchedClassifier, anyhow::Error> {
+    Ok(bpf
+        .program_mut("github_hosts_queries")
+        .unwrap()
+        .try_into()?)
+}
+
+impl Attacher {
+    pub fn load(&self, bpf: &mut Bpf) -> Result<(), anyhow::Error> {
+        if self.track_queries {
+            // the tracking classifier would go away on exit while the
+            // pinned program keeps checking for queries
+            if self.pin {
+                bail!("--verify-queries and --measure-rtt can't be used with --pin");
+            }
+            queries(bpf)?.load()?;
+        }
+        match self.hook {
+            Hook::Xdp => xdp(bpf)?.load()?,
+            Hook::Tc => {
+                if self.pin {
+                    bail!("--pin is only supported with --hook xdp");
+                }
+                classifier(bpf)?.load()?
+            }
+        }
+        Ok(())
+    }
+
+    fn link_path(&self, iface: &str) -> PathBuf {
+        self.bpffs.join(format!("link_{}", iface))
+    }
+
+    pub fn attach(&self, bpf: &mut Bpf, iface: &str) -> Result<Vec<Link>, anyhow::Error> {
+        let mut links = vec![];
+        if self.hook == Hook::Tc || self.track_queries {
+            // fails if the qdisc is already there, which is fine
+            let _ = tc::qdisc_add_clsact(iface);
+        }
+        if self.track_queries {
+            let id = queries(bpf)?.attach(iface, TcAttachType::Egress)?;
+            links.push(Link::Queries(id));
+        }
+        match self.hook {
+            Hook::Xdp => links.push(self.attach_xdp(xdp(bpf)?, iface)?),
+            Hook::Tc => {
+                let program = classifier(bpf)?;
+                for direction in &self.tc_directions {
+                    links.push(Link::Tc(program.attach(iface, direction.attach_type())?));
+                    info!("{}: attached to tc {:?}", iface, direction);
+                }
+            }
+        }
+        Ok(links)
+    }
+
+    /// Attach in the `--xdp-mode` mode. A link pinned by an earlier `--pin`
+    /// run is taken over as is.
+    fn attach_xdp(&self, program: &mut Xdp, iface: &str) -> Result<Link, anyhow::Error> {
+        let link = self.link_path(iface);
+        if link.exists() {
+            pin::update_xdp(program.fd().unwrap(), &link)?;
+            info!("{}: took over the link pinned at {}", iface, link.display());
+            return Ok(Link::Pinned);
+        }
+        // loopback has no driver mode
+        let modes = if iface == "lo" {
+            XdpMode::Skb.flags()
+        } else {
+            self.xdp_mode.flags()
+        };
+        for (i, (name, flags)) in modes.iter().enumerate() {
+            let r = if self.pin {
+                pin::attach_xdp(program.fd().unwrap(), iface, *flags, &link).map(|_| Link::Pinned)
+            } else {
+                program
+                    .attach(iface, *flags)
+                    .map(Link::Xdp)
+                    .map_err(Into::into)
+            };
+            match r {
+                Ok(link) => {
+                    info!("{}: attached in {} mode", iface, name);
+                    return Ok(link);
+                }
+                Err(e) if i + 1 < modes.len() => {
+                    warn!("{}: {} mode failed, falling back: {:#}", iface, name, e)
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "failed to attach the XDP program to {} in {} mode",
+                        iface, name
+                    )))
+                }
+            }
+        }
+        bail!("no XDP mode to attach {} in", iface)
+    }
+
+    pub fn detach(
+        &self,
+        bpf: &mut Bpf,
+        iface: &str,
+        links: Vec<Link>,
+    ) -> Result<(), anyhow::Error> {
+        for link in links {
+            match link {
+                Link::Xdp(id) => xdp(bpf)?.detach(id)?,
+                Link::Tc(id) => classifier(bpf)?.detach(id)?,
+                Link::Queries(id) => queries(bpf)?.detach(id)?,
+                Link::Pinned => {
+                    let _ = std::fs::remove_file(self.link_path(iface));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,563 @@
+//! Control interface of a running instance.
+//!
+//! The daemon listens on a Unix socket; a client sends one command per
+//! connection as a single line, e.g. `add github.com 140.82.113.4`, and reads
+//! the response until the daemon closes the connection. Failed commands get a
+//! response starting with `error: `.
+//!
+//! Without a running instance, `list`, `add`, `remove`, `stats`, `rtt`,
+//! `dry-run` and `bypass` work on the maps left pinned by `run --pin` instead.
+//! `list` then only knows the installed rules, not their windows.
+
+use std::io;
+use std::ops::DerefMut;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use aya::maps::{Array, Map, MapRef, MapRefMut, PerCpuArray, PerCpuHashMap};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::warn;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+
+use github_hosts_common::hosts::Window;
+use github_hosts_common::name::{encode, escape, normalize, show, wire_len};
+use github_hosts_common::{
+    Action, RttHistogram, RuleKey, RuleValue, CONFIG_FLAGS, DEFAULT_POLICY, FLAG_BYPASS,
+    FLAG_DRY_RUN, STAT_MAX, STAT_NAMES,
+};
+
+use crate::pin::PinnedMap;
+use crate::rtt;
+use crate::rules::Rules;
+use crate::{check_pinned, PinOptions};
+
+pub const DEFAULT_SOCKET: &str = "/run/github-hosts.sock";
+
+/// Longest command line read from a client.
+const MAX_LINE: u64 = 4096;
+/// How long a client has to send its command.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// List the installed rules, and the ones waiting for their time window
+    List,
+    /// Add a rule, overriding the configured ones until restart
+    Add {
+        /// Only for the clients of this policy
+        #[clap(long, default_value_t = DEFAULT_POLICY)]
+        policy: u32,
+        host: String,
+        /// An address, `0.0.0.0` to sinkhole the host, `nxdomain` or `pass`
+        rule: RuleValue,
+    },
+    /// Remove a rule, hiding the configured ones until restart
+    Remove {
+        #[clap(long, default_value_t = DEFAULT_POLICY)]
+        policy: u32,
+        host: String,
+    },
+    /// Show how queries for a host are handled
+    Test {
+        /// As seen by the clients of this policy
+        #[clap(long, default_value_t = DEFAULT_POLICY)]
+        policy: u32,
+        host: String,
+    },
+    /// Dump the eBPF program's counters
+    Stats,
+    /// Show the response times of the resolvers, with `--measure-rtt`
+    Rtt,
+    /// Re-read the hosts files
+    Reload,
+    /// Only report what would be rewritten, see `run --dry-run`
+    DryRun {
+        #[clap(value_enum)]
+        state: Toggle,
+    },
+    /// Let every packet through untouched, or resume rewriting. SIGUSR1
+    /// toggles it too
+    Bypass {
+        #[clap(value_enum)]
+        state: Toggle,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+impl Toggle {
+    fn as_str(self) -> &'static str {
+        match self {
+            Toggle::On => "on",
+            Toggle::Off => "off",
+        }
+    }
+}
+
+impl Command {
+    fn to_line(&self) -> String {
+        match self {
+            Command::List => "list".to_string(),
+            Command::Add { policy, host, rule } => {
+                format!("add --policy {} {} {}", policy, host, rule)
+            }
+            Command::Remove { policy, host } => format!("remove --policy {} {}", policy, host),
+            Command::Test { policy, host } => format!("test --policy {} {}", policy, host),
+            Command::Stats => "stats".to_string(),
+            Command::Rtt => "rtt".to_string(),
+            Command::Reload => "reload".to_string(),
+            Command::DryRun { state } => format!("dry-run {}", state.as_str()),
+            Command::Bypass { state } => format!("bypass {}", state.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[clap(no_binary_name = true)]
+struct Line {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+fn parse_line(line: &str) -> Result<Command, String> {
+    Line::try_parse_from(line.split_whitespace())
+        .map(|l| l.command)
+        .map_err(|e| {
+            let e = e.to_string();
+            let first = e.lines().next().unwrap_or_default();
+            first.strip_prefix("error: ").unwrap_or(first).to_string()
+        })
+}
+
+/// A command received on the socket and where to send its response.
+pub type Request = (Command, oneshot::Sender<String>);
+
+/// Listen on `path`, forwarding commands to `tx`.
+pub fn serve(path: &Path, tx: UnboundedSender<Request>) -> Result<(), anyhow::Error> {
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!(
+            "{} is in use, is github-hosts already running?",
+            path.display()
+        );
+    }
+    match std::fs::symlink_metadata(path) {
+        // left over by an instance that didn't exit cleanly
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("control socket: {}", e);
+                    continue;
+                }
+            };
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_one(stream, tx).await {
+                    warn!("control socket: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn serve_one(stream: UnixStream, tx: UnboundedSender<Request>) -> std::io::Result<()> {
+    let (r, mut w) = stream.into_split();
+    let mut line = String::new();
+    let mut r = BufReader::new(r.take(MAX_LINE));
+    tokio::time::timeout(READ_TIMEOUT, r.read_line(&mut line))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no command received"))??;
+    let response = match parse_line(&line) {
+        Ok(command) => {
+            let (reply, response) = oneshot::channel();
+            let _ = tx.send((command, reply));
+            response
+                .await
+                .unwrap_or_else(|_| "error: shutting down\n".to_string())
+        }
+        Err(e) => format!("error: {}\n", e),
+    };
+    w.write_all(response.as_bytes()).await
+}
+
+/// Run `command` against the daemon state, returning the response text.
+pub fn handle(
+    command: Command,
+    rules: &mut Rules,
+    config: &mut Array<MapRefMut, u32>,
+    stats: &PerCpuArray<MapRef, u64>,
+    rtt: &PerCpuHashMap<MapRef, u32, RttHistogram>,
+) -> Result<String, anyhow::Error> {
+    let mut out = String::new();
+    match command {
+        Command::List => {
+            let effective = rules.effective();
+            let window_now = |scoped| effective.get(scoped).and_then(|(_, w)| w.as_ref());
+            for (scoped, rule) in rules.installed() {
+                let window = window_now(scoped).map(|w| (w, true));
+                out += &format_rule(scoped.0, &scoped.1, rule, window);
+            }
+            // and those waiting for their window
+            for (scoped, rule, window) in &rules.scheduled() {
+                if window_now(scoped) != Some(window) {
+                    out += &format_rule(scoped.0, &scoped.1, rule, Some((window, false)));
+                }
+            }
+        }
+        Command::Add { policy, host, rule } => {
+            rules.set_override(policy, &host, Some(rule));
+            rules.sync()?;
+        }
+        Command::Remove { policy, host } => {
+            rules.set_override(policy, &host, None);
+            rules.sync()?;
+        }
+        Command::Test { policy, host } => {
+            let host = normalize(&host);
+            // errors on names that can't be a key, such as names with more
+            // labels than the program follows
+            let key = encode(&host)?;
+            out += &format!("key: {}\n", escape(&key[..wire_len(&key)]));
+            match rules.desired().get(&host) {
+                Some(ips) => {
+                    out += "candidates:";
+                    for ip in ips {
+                        out += &format!(" {}", ip);
+                        if rules.is_down(&host, *ip) {
+                            out += " (down)";
+                        }
+                    }
+                    out += "\n";
+                }
+                None => out += "candidates: none\n",
+            }
+            // as looked up by the program, see `CLIENTS`
+            let installed = rules.installed();
+            let rule = installed
+                .get(&(policy, host.clone()))
+                .or_else(|| installed.get(&(DEFAULT_POLICY, host.clone())));
+            match rule {
+                Some(rule) => match rule.action() {
+                    Some(Action::Rewrite) => out += &format!("rewritten to: {}\n", rule),
+                    Some(Action::Sinkhole) => out += "blocked: answered with 0.0.0.0\n",
+                    Some(Action::Nxdomain) => out += "blocked: answered with NXDOMAIN\n",
+                    Some(Action::Pass) | None => {
+                        out += "rewritten to: nothing, answers pass unchanged\n"
+                    }
+                },
+                None => out += "rewritten to: nothing, answers pass unchanged\n",
+            }
+        }
+        Command::Stats => {
+            let mut totals = vec![];
+            for index in 0..STAT_MAX {
+                totals.push(stats.get(&index, 0)?.iter().sum());
+            }
+            out += &format_stats(&totals);
+        }
+        Command::Rtt => {
+            let mut resolvers = vec![];
+            for item in rtt.iter() {
+                let (resolver, per_cpu) = item?;
+                resolvers.push((resolver, per_cpu.to_vec()));
+            }
+            out += &rtt::format(resolvers);
+        }
+        Command::DryRun { state } => set_flag(config, FLAG_DRY_RUN, state)?,
+        Command::Bypass { state } => set_flag(config, FLAG_BYPASS, state)?,
+        Command::Reload => {
+            rules.reload_files()?;
+            rules.sync()?;
+        }
+    }
+    Ok(out)
+}
+
+/// A `list` line, in hosts file syntax, with the window of a scheduled rule
+/// and whether it is installed.
+fn format_rule(
+    policy: u32,
+    host: &str,
+    rule: &RuleValue,
+    window: Option<(&Window, bool)>,
+) -> String {
+    let mut notes = vec![];
+    if policy != DEFAULT_POLICY {
+        notes.push(format!("policy {}", policy));
+    }
+    match window {
+        Some((window, true)) => notes.push(format!("active {}", window)),
+        Some((window, false)) => notes.push(format!("active {} (inactive)", window)),
+        None => {}
+    }
+    if notes.is_empty() {
+        format!("{} {}\n", rule, host)
+    } else {
+        format!("{} {} # {}\n", rule, host, notes.join(", "))
+    }
+}
+
+/// Run `command` directly against the pinned maps. Changes last until an
+/// instance starts and installs its own rules.
+pub fn handle_pinned(command: Command, opts: &PinOptions) -> Result<String, anyhow::Error> {
+    check_pinned(&opts.bpffs)?;
+    let open = |name: &str| PinnedMap::open(&opts.bpffs.join(name));
+    let mut out = String::new();
+    match command {
+        Command::List => {
+            let map = open("GITHUB_HOSTS")?;
+            for k in map.keys::<RuleKey>()? {
+                // gone since listed
+                if let Some(v) = map.get::<_, RuleValue>(&k)? {
+                    out += &format_rule(k.policy, &show(&k.name), &v, None);
+                }
+            }
+        }
+        Command::Add { policy, host, rule } => {
+            let key = RuleKey::from_host(policy, &normalize(&host))?;
+            open("GITHUB_HOSTS")?.insert(&key, &rule)?;
+        }
+        Command::Remove { policy, host } => {
+            let key = RuleKey::from_host(policy, &normalize(&host))?;
+            open("GITHUB_HOSTS")?.remove(&key)?;
+        }
+        Command::Stats => {
+            let stats = open("STATS")?;
+            let mut totals = vec![];
+            for index in 0..STAT_MAX {
+                let per_cpu = stats.get_per_cpu::<_, u64>(&index)?;
+                totals.push(per_cpu.unwrap_or_default().iter().sum());
+            }
+            out += &format_stats(&totals);
+        }
+        Command::Rtt => {
+            let rtt = open("RTT")?;
+            let mut resolvers = vec![];
+            for resolver in rtt.keys::<u32>()? {
+                if let Some(per_cpu) = rtt.get_per_cpu(&resolver)? {
+                    resolvers.push((resolver, per_cpu));
+                }
+            }
+            out += &rtt::format(resolvers);
+        }
+        Command::DryRun { state } => {
+            let config = open("CONFIG")?;
+            let flags = config.get(&CONFIG_FLAGS)?.unwrap_or_default();
+            config.insert(&CONFIG_FLAGS, &toggle(flags, FLAG_DRY_RUN, state))?;
+        }
+        Command::Bypass { state } => {
+            let config = open("CONFIG")?;
+            let flags = config.get(&CONFIG_FLAGS)?.unwrap_or_default();
+            config.insert(&CONFIG_FLAGS, &toggle(flags, FLAG_BYPASS, state))?;
+        }
+        Command::Test { .. } | Command::Reload => {
+            bail!("`{}` needs a running instance", command.to_line())
+        }
+    }
+    Ok(out)
+}
+
+fn toggle(flags: u32, flag: u32, state: Toggle) -> u32 {
+    match state {
+        Toggle::On => flags | flag,
+        Toggle::Off => flags & !flag,
+    }
+}
+
+/// Set or clear `flag` in `CONFIG`, leaving the other flags alone.
+pub fn set_flag<T: DerefMut<Target = Map>>(
+    config: &mut Array<T, u32>,
+    flag: u32,
+    state: Toggle,
+) -> Result<(), anyhow::Error> {
+    let flags = config.get(&CONFIG_FLAGS, 0)?;
+    config.set(CONFIG_FLAGS, toggle(flags, flag, state), 0)?;
+    Ok(())
+}
+
+/// One `name total` line per counter, from the totals over the CPUs.
+fn format_stats(totals: &[u64]) -> String {
+    let mut out = String::new();
+    for (name, total) in STAT_NAMES.iter().zip(totals) {
+        out += &format!("{} {}\n", name, total);
+    }
+    out
+}
+
+/// Where to find a running instance, or the maps it left pinned.
+#[derive(Debug, Args)]
+pub struct Target {
+    /// Control socket of the running instance
+    #[clap(short, long, default_value = DEFAULT_SOCKET)]
+    socket: PathBuf,
+    #[clap(flatten)]
+    pin: PinOptions,
+}
+
+#[derive(Debug, Args)]
+pub struct Options {
+    #[clap(flatten)]
+    target: Target,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+async fn exchange(mut stream: UnixStream, command: &Command) -> Result<String, anyhow::Error> {
+    stream
+        .write_all(format!("{}\n", command.to_line()).as_bytes())
+        .await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    if let Some(e) = response.strip_prefix("error: ") {
+        bail!("{}", e.trim_end());
+    }
+    Ok(response)
+}
+
+/// Run `command` on the instance listening on the socket of `target`, or
+/// on the maps it left pinned if none is.
+pub async fn send(command: Command, target: &Target) -> Result<String, anyhow::Error> {
+    match UnixStream::connect(&target.socket).await {
+        Ok(stream) => exchange(stream, &command).await,
+        // no instance, or one that didn't get to remove its socket
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            handle_pinned(command, &target.pin)
+        }
+        Err(e) => Err(anyhow::Error::new(e)
+            .context(format!("failed to connect to {}", target.socket.display()))),
+    }
+}
+
+pub async fn client(opts: Options) -> Result<(), anyhow::Error> {
+    print!("{}", send(opts.command, &opts.target).await?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_line() {
+        let commands = [
+            Command::List,
+            Command::Add {
+                policy: DEFAULT_POLICY,
+                host: "github.com".into(),
+                rule: "140.82.113.4".parse().unwrap(),
+            },
+            Command::Add {
+                policy: 2,
+                host: "ads.example.com".into(),
+                rule: "nxdomain".parse().unwrap(),
+            },
+            Command::Remove {
+                policy: DEFAULT_POLICY,
+                host: "github.com".into(),
+            },
+            Command::Test {
+                policy: 2,
+                host: "github.com".into(),
+            },
+            Command::Stats,
+            Command::Rtt,
+            Command::Reload,
+            Command::DryRun { state: Toggle::Off },
+            Command::Bypass { state: Toggle::On },
+        ];
+        for command in commands {
+            assert_eq!(parse_line(&command.to_line()), Ok(command));
+        }
+        assert_eq!(parse_line("  stats \n"), Ok(Command::Stats));
+        assert_eq!(
+            parse_line("remove github.com"),
+            Ok(Command::Remove {
+                policy: DEFAULT_POLICY,
+                host: "github.com".into()
+            })
+        );
+        assert!(parse_line("add github.com 256.0.0.1").is_err());
+        assert!(parse_line("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_format_rule() {
+        let rule: RuleValue = "140.82.113.4".parse().unwrap();
+        let day: Window = "09:00-18:00".parse().unwrap();
+        assert_eq!(
+            format_rule(DEFAULT_POLICY, "github.com", &rule, None),
+            "140.82.113.4 github.com\n"
+        );
+        assert_eq!(
+            format_rule(2, "github.com", &rule, Some((&day, true))),
+            "140.82.113.4 github.com # policy 2, active 09:00-18:00\n"
+        );
+        assert_eq!(
+            format_rule(DEFAULT_POLICY, "github.com", &rule, Some((&day, false))),
+            "140.82.113.4 github.com # active 09:00-18:00 (inactive)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_socket() {
+        let path =
+            std::env::temp_dir().join(format!("github-hosts-ctl-{}.sock", std::process::id()));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        serve(&path, tx).unwrap();
+        tokio::spawn(async move {
+            while let Some((command, reply)) = rx.recv().await {
+                let response = match command {
+                    Command::Stats => "packets 1\n".to_string(),
+                    _ => "error: not here\n".to_string(),
+                };
+                reply.send(response).unwrap();
+            }
+        });
+
+        let target = Target {
+            socket: path.clone(),
+            pin: PinOptions {
+                bpffs: std::env::temp_dir().join("github-hosts-ctl-nothing-pinned"),
+            },
+        };
+        assert_eq!(send(Command::Stats, &target).await.unwrap(), "packets 1\n");
+        let e = send(Command::Reload, &target).await.unwrap_err();
+        assert_eq!(e.to_string(), "not here");
+        // the socket of a running instance is left alone
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        assert!(serve(&path, tx).is_err());
+        // as is anything that isn't a socket
+        let file = path.with_extension("txt");
+        std::fs::write(&file, "").unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        assert!(serve(&file, tx).is_err());
+        std::fs::remove_file(&file).unwrap();
+
+        // without an instance, the pinned maps are looked for
+        std::fs::remove_file(&path).unwrap();
+        let e = send(Command::Stats, &target).await.unwrap_err();
+        assert!(e.to_string().starts_with("no maps pinned in "));
+    }
+}
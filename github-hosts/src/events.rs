@@ -0,0 +1,97 @@
+//! What the eBPF program would have done to the responses, reported on the
+//! `EVENTS` perf buffer with `--dry-run`.
+
+use std::mem;
+use std::net::Ipv4Addr;
+
+use aya::maps::perf::AsyncPerfEventArray;
+use aya::util::online_cpus;
+use aya::Bpf;
+use bytes::BytesMut;
+use log::warn;
+
+use github_hosts_common::name::show;
+use github_hosts_common::{Action, RuleEvent, DEFAULT_POLICY};
+
+/// One line describing `event`.
+pub fn format(event: &RuleEvent) -> String {
+    let mut out = format!("dry run: {}", show(&event.key.name));
+    if event.key.policy != DEFAULT_POLICY {
+        out += &format!(" (policy {})", event.key.policy);
+    }
+    out += &format!(" for {}: ", Ipv4Addr::from(event.client.to_ne_bytes()));
+    match event.rule.action() {
+        Some(Action::Nxdomain) => out += "would answer NXDOMAIN",
+        _ => {
+            out += &format!(
+                "would rewrite {} to {}",
+                Ipv4Addr::from(event.old),
+                event.rule
+            )
+        }
+    }
+    out
+}
+
+/// Print the events of every CPU as they come. The events only flow while
+/// `FLAG_DRY_RUN` is set, which can change at runtime.
+pub fn spawn(bpf: &mut Bpf) -> Result<(), anyhow::Error> {
+    let mut events = AsyncPerfEventArray::try_from(bpf.map_mut("EVENTS")?)?;
+    for cpu in online_cpus()? {
+        let mut buf = events.open(cpu, None)?;
+        tokio::spawn(async move {
+            let mut buffers: Vec<BytesMut> = (0..16)
+                .map(|_| BytesMut::with_capacity(mem::size_of::<RuleEvent>()))
+                .collect();
+            loop {
+                let read = match buf.read_events(&mut buffers).await {
+                    Ok(read) => read,
+                    Err(e) => {
+                        warn!("dry run events of cpu {}: {}", cpu, e);
+                        return;
+                    }
+                };
+                if read.lost > 0 {
+                    warn!("dry run: {} events lost", read.lost);
+                }
+                for data in &buffers[..read.read] {
+                    if data.len() < mem::size_of::<RuleEvent>() {
+                        continue;
+                    }
+                    // perf samples are only 4 bytes aligned
+                    let event = unsafe { (data.as_ptr() as *const RuleEvent).read_unaligned() };
+                    println!("{}", format(&event));
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use github_hosts_common::{RuleKey, RuleValue, NAME_LEN};
+
+    #[test]
+    fn test_format() {
+        let mut name = [0u8; NAME_LEN];
+        name[..12].copy_from_slice(b"\x06github\x03com\x00");
+        let mut event = RuleEvent {
+            key: RuleKey { policy: 2, name },
+            rule: "140.82.113.4".parse().unwrap(),
+            client: u32::from_ne_bytes([10, 0, 8, 5]),
+            old: [20, 205, 243, 166],
+        };
+        assert_eq!(
+            format(&event),
+            "dry run: github.com (policy 2) for 10.0.8.5: would rewrite 20.205.243.166 to 140.82.113.4"
+        );
+        event.key.policy = DEFAULT_POLICY;
+        event.rule = "nxdomain".parse::<RuleValue>().unwrap();
+        assert_eq!(
+            format(&event),
+            "dry run: github.com for 10.0.8.5: would answer NXDOMAIN"
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::{mpsc::UnboundedSender, watch};
+use tokio::task::JoinSet;
+
+use crate::probe::Prober;
+use crate::rules::Update;
+use crate::sources::Hosts;
+
+#[derive(Debug, Default)]
+struct Target {
+    down: bool,
+    /// consecutive checks contradicting `down`
+    streak: u32,
+}
+
+/// Turns check results into up/down transitions: a target goes down after
+/// `fall` consecutive failed checks and comes back after `rise` consecutive
+/// successful ones. Targets start up.
+pub struct Tracker {
+    fall: u32,
+    rise: u32,
+    targets: HashMap<(String, Ipv4Addr), Target>,
+}
+
+impl Tracker {
+    pub fn new(fall: u32, rise: u32) -> Self {
+        Tracker {
+            fall,
+            rise,
+            targets: HashMap::new(),
+        }
+    }
+
+    /// Record one check, returns `Some(up)` if the target changed state.
+    pub fn record(&mut self, host: &str, ip: Ipv4Addr, ok: bool) -> Option<bool> {
+        let target = self.targets.entry((host.to_string(), ip)).or_default();
+        if ok != target.down {
+            target.streak = 0;
+            return None;
+        }
+        target.streak += 1;
+        let threshold = if target.down { self.rise } else { self.fall };
+        if target.streak < threshold {
+            return None;
+        }
+        target.down = !target.down;
+        target.streak = 0;
+        Some(!target.down)
+    }
+}
+
+/// Check every candidate of every host each `interval`, sending state changes
+/// to `tx`. A host whose candidates are all down has its rule removed until
+/// one of them recovers.
+pub fn spawn(
+    prober: Prober,
+    mut tracker: Tracker,
+    interval: Duration,
+    candidates: watch::Receiver<Hosts>,
+    tx: UnboundedSender<Update>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let hosts = candidates.borrow().clone();
+            let mut checks = JoinSet::new();
+            for (host, ips) in hosts {
+                for ip in ips {
+                    let prober = prober.clone();
+                    let host = host.clone();
+                    checks.spawn(async move {
+                        let ok = prober.measure(&host, ip).await.is_some();
+                        (host, ip, ok)
+                    });
+                }
+            }
+            while let Some(r) = checks.join_next().await {
+                let (host, ip, ok) = r.expect("health check task panicked");
+                let up = match tracker.record(&host, ip, ok) {
+                    Some(up) => up,
+                    None => continue,
+                };
+                if up {
+                    info!("{} {} recovered, restoring it", host, ip);
+                } else {
+                    warn!("{} {} is down, falling back", host, ip);
+                }
+                if tx.send(Update::Health { host, ip, up }).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker() {
+        let ip = Ipv4Addr::new(140, 82, 113, 4);
+        let mut t = Tracker::new(3, 2);
+
+        assert_eq!(t.record("github.com", ip, false), None);
+        assert_eq!(t.record("github.com", ip, false), None);
+        // a success resets the streak
+        assert_eq!(t.record("github.com", ip, true), None);
+        assert_eq!(t.record("github.com", ip, false), None);
+        assert_eq!(t.record("github.com", ip, false), None);
+        assert_eq!(t.record("github.com", ip, false), Some(false));
+        assert_eq!(t.record("github.com", ip, false), None);
+
+        assert_eq!(t.record("github.com", ip, true), None);
+        assert_eq!(t.record("github.com", ip, true), Some(true));
+        assert_eq!(t.record("github.com", ip, true), None);
+
+        // targets are tracked per host and ip
+        assert_eq!(t.record("api.github.com", ip, false), None);
+    }
+}
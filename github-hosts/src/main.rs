@@ -1,21 +1,252 @@
-use anyhow::Context;
-use aya::programs::{Xdp, XdpFlags};
-use aya::{include_bytes_aligned, Bpf};
+use anyhow::{bail, Context};
+use aya::{include_bytes_aligned, Bpf, BpfLoader};
 use aya_log::BpfLogger;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 use log::{info, warn};
 use tokio::signal;
+use tokio::signal::unix::SignalKind;
 
-use std::io::BufRead;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use aya::maps::HashMap;
-//use github_hosts_common::BackendPorts;
+use aya::maps::lpm_trie::{Key, LpmTrie};
+use aya::maps::{Array, HashMap, PerCpuArray, PerCpuHashMap};
+use github_hosts_common::hosts::Severity;
+use github_hosts_common::name::{decode, encode, escape, unescape, wire_len};
+use github_hosts_common::rule::Rule;
+use github_hosts_common::{
+    RuleKey, RuleValue, CONFIG_FLAGS, FLAG_AUTHORITATIVE, FLAG_BYPASS, FLAG_DRY_RUN, FLAG_RTT,
+    FLAG_VERIFY,
+};
+
+mod attach;
+mod ctl;
+mod events;
+mod health;
+mod netlink;
+mod pcap;
+mod pin;
+mod probe;
+mod replay;
+mod rtt;
+mod rules;
+mod sources;
+mod subscription;
+
+use netlink::LinkEvent;
+use rules::Rules;
+use subscription::Subscription;
 
 #[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    /// Without a subcommand, attach to the interface and rewrite DNS answers
+    #[clap(flatten)]
+    run: RunOptions,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attach to the interface and rewrite DNS answers, the default
+    Run(RunOptions),
+    /// Validate hosts files and rules, without loading anything into the kernel
+    Check(SourceOptions),
+    /// Print the rules of a running instance, or of the maps pinned by a
+    /// `--pin` one, like `ctl list`
+    List(ctl::Target),
+    /// Print the counters of a running instance, like `ctl stats`
+    Stats(ctl::Target),
+    /// Print the resolver response times measured by a running instance,
+    /// like `ctl rtt`
+    Rtt(ctl::Target),
+    /// Detach the program left attached by `run --pin` and drop its maps
+    Detach(PinOptions),
+    /// Print the DNS wire format of a hostname, as used for map keys
+    Encode { host: String },
+    /// Print the hostname of a DNS wire format name, written as
+    /// `\x06github\x03com\x00` or as hex
+    Decode { name: String },
+    /// Talk to a running instance over its control socket
+    Ctl(ctl::Options),
+    /// Apply the rules to the packets of a pcap file and write the result,
+    /// printing what changed. Needs no privileges
+    Replay(ReplayOptions),
+}
+
+/// Where the maps, and with `--pin` the XDP link, are pinned.
+const DEFAULT_BPFFS: &str = "/sys/fs/bpf/github-hosts";
+
+#[derive(Debug, Args)]
+struct PinOptions {
+    /// bpffs directory the maps and links are pinned in
+    #[clap(long, default_value = DEFAULT_BPFFS)]
+    bpffs: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct SourceOptions {
+    /// Hosts file or directory of hosts files, may be repeated. Later sources
+    /// override earlier ones
+    #[clap(
+        short = 'H',
+        long = "hosts",
+        env = "GITHUB_HOSTS_FILES",
+        value_delimiter = ':',
+        default_value = "github-hosts.txt"
+    )]
+    hosts: Vec<PathBuf>,
+    /// Extra `host=ip` rule applied on top of the hosts files, may be
+    /// repeated. `policy:host=ip` scopes it to the clients of a policy and
+    /// `host=ip@09:00-18:00` only applies it during that window of the day
+    #[clap(short = 'r', long = "extra-rule")]
+    extra_rules: Vec<Rule>,
+}
+
+#[derive(Debug, Args)]
+struct RunOptions {
     //#[clap(short, long, default_value = "lo")]
-    #[clap(short, long, default_value = "eth0")]
-    iface: String,
+    /// Interface to attach to, may be repeated or comma separated
+    #[clap(
+        short,
+        long = "iface",
+        value_delimiter = ',',
+        required_unless_present_any = ["all", "loopback"]
+    )]
+    ifaces: Vec<String>,
+    /// Attach to every interface but loopback, including the ones added
+    /// later on. See `--loopback`
+    #[clap(long, conflicts_with = "ifaces")]
+    all: bool,
+    /// Also attach to `lo`, to rewrite the answers of a resolver running on
+    /// this host, e.g. systemd-resolved or dnsmasq, served from its cache
+    #[clap(long)]
+    loopback: bool,
+    /// Hook the rewrite program into XDP, or into TC
+    #[clap(long, value_enum, default_value = "xdp")]
+    hook: attach::Hook,
+    /// Answer A queries for hosts with a rule right from XDP, without waiting
+    /// for the upstream resolver. For a host clients send their queries
+    /// through, e.g. the router or the resolver itself
+    #[clap(long)]
+    authoritative: bool,
+    /// Only rewrite responses to a query seen leaving the interface, leaving
+    /// spoofed ones alone. Attaches a TC egress classifier to track queries
+    #[clap(long)]
+    verify_queries: bool,
+    /// Time the responses of the resolvers, see the `rtt` command. Tracks
+    /// queries like `--verify-queries`
+    #[clap(long)]
+    measure_rtt: bool,
+    /// Only print what would be rewritten or blocked, leaving the packets
+    /// alone. `ctl dry-run on|off` switches it at runtime
+    #[clap(long)]
+    dry_run: bool,
+    /// Directions the TC classifier is attached to, with `--hook tc`
+    #[clap(long, value_enum, value_delimiter = ',', default_value = "ingress")]
+    tc_direction: Vec<attach::Direction>,
+    /// How the XDP program is attached, `auto` tries `native` then `skb`
+    #[clap(long, value_enum, default_value = "auto")]
+    xdp_mode: attach::XdpMode,
+    /// `cidr=policy`: clients in the subnet get the rules of the policy,
+    /// falling back to the default ones. May be repeated
+    #[clap(long = "client", value_parser = parse_client)]
+    clients: Vec<ClientPolicy>,
+    #[clap(flatten)]
+    sources: SourceOptions,
+    #[clap(flatten)]
+    pinning: PinOptions,
+    /// Leave the program attached and the maps pinned on exit, so answers
+    /// keep being rewritten while the daemon is stopped or restarting
+    #[clap(long)]
+    pin: bool,
+    /// URL of a remote hosts list to fetch periodically, may be repeated.
+    /// Local hosts files override it
+    #[clap(long = "subscribe")]
+    subscriptions: Vec<String>,
+    /// Seconds between two fetches of the remote hosts lists
+    #[clap(long, default_value = "3600")]
+    refresh_interval: u64,
+    /// Where the last good copy of each remote hosts list is kept
+    #[clap(long, default_value = "/var/cache/github-hosts")]
+    cache_dir: PathBuf,
+    /// Probe the candidate IPs of hosts listed with several addresses and
+    /// use the fastest one
+    #[clap(long, value_enum)]
+    probe: Option<probe::Mode>,
+    /// Port probed on each candidate
+    #[clap(long, default_value = "443")]
+    probe_port: u16,
+    /// Seconds between two probing rounds
+    #[clap(long, default_value = "300")]
+    probe_interval: u64,
+    /// How much faster, as a fraction of the current latency, a candidate
+    /// has to be in two rounds in a row to replace the current one
+    #[clap(long, default_value = "0.2")]
+    probe_hysteresis: f64,
+    /// Check every candidate IP periodically, falling back to another
+    /// candidate, or to the real DNS answer, while one is down
+    #[clap(long)]
+    health_check: bool,
+    /// Seconds between two health checks
+    #[clap(long, default_value = "30")]
+    health_interval: u64,
+    /// Consecutive failed checks before a target is considered down
+    #[clap(long, default_value = "3")]
+    health_fall: u32,
+    /// Consecutive successful checks before a down target is restored
+    #[clap(long, default_value = "2")]
+    health_rise: u32,
+    /// Unix socket to listen on for `ctl` commands
+    #[clap(long, default_value = ctl::DEFAULT_SOCKET)]
+    control_socket: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct ReplayOptions {
+    /// Capture to read, classic pcap of Ethernet frames
+    input: PathBuf,
+    /// Where to write the packets once rewritten
+    #[clap(short, long)]
+    output: PathBuf,
+    /// `cidr=policy`, as for `run`
+    #[clap(long = "client", value_parser = parse_client)]
+    clients: Vec<ClientPolicy>,
+    #[clap(flatten)]
+    sources: SourceOptions,
+}
+
+/// An entry of the `CLIENTS` LPM trie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClientPolicy {
+    addr: Ipv4Addr,
+    prefix_len: u32,
+    policy: u32,
+}
+
+fn parse_client(s: &str) -> Result<ClientPolicy, String> {
+    let (cidr, policy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `cidr=policy`, got `{}`", s))?;
+    let (addr, prefix_len) = cidr.split_once('/').unwrap_or((cidr, "32"));
+    let addr = addr
+        .parse()
+        .map_err(|e| format!("invalid IPv4 address `{}`: {}", addr, e))?;
+    let prefix_len = match prefix_len.parse() {
+        Ok(len) if len <= 32 => len,
+        _ => return Err(format!("invalid prefix length `{}`", prefix_len)),
+    };
+    let policy = policy
+        .parse()
+        .map_err(|_| format!("invalid policy `{}`", policy))?;
+    Ok(ClientPolicy {
+        addr,
+        prefix_len,
+        policy,
+    })
 }
 
 #[tokio::main]
@@ -24,125 +255,375 @@ async fn main() -> Result<(), anyhow::Error> {
 
     env_logger::init();
 
+    match opt.command {
+        Some(Command::Run(opts)) => run(opts).await,
+        Some(Command::Check(opts)) => check(opts),
+        Some(Command::List(opts)) => {
+            print!("{}", ctl::send(ctl::Command::List, &opts).await?);
+            Ok(())
+        }
+        Some(Command::Stats(opts)) => {
+            print!("{}", ctl::send(ctl::Command::Stats, &opts).await?);
+            Ok(())
+        }
+        Some(Command::Rtt(opts)) => {
+            print!("{}", ctl::send(ctl::Command::Rtt, &opts).await?);
+            Ok(())
+        }
+        Some(Command::Detach(opts)) => detach(opts),
+        Some(Command::Encode { host }) => {
+            let key = encode(&host)?;
+            println!("{}", escape(&key[..wire_len(&key)]));
+            Ok(())
+        }
+        Some(Command::Decode { name }) => {
+            println!("{}", decode(&unescape(&name)?)?);
+            Ok(())
+        }
+        Some(Command::Ctl(opts)) => ctl::client(opts).await,
+        Some(Command::Replay(opts)) => replay::replay(opts),
+        None => run(opt.run).await,
+    }
+}
+
+/// The maps the eBPF program declares as pinned, by name.
+const PINNED_MAPS: &[&str] = &[
+    "GITHUB_HOSTS",
+    "CLIENTS",
+    "STATS",
+    "CONFIG",
+    "QUERIES",
+    "RTT",
+];
+
+/// Whether maps pinned by another instance are in `bpffs`.
+fn has_pins(bpffs: &Path) -> bool {
+    bpffs.join("GITHUB_HOSTS").exists()
+}
+
+/// Unlink the maps pinned in `bpffs`, then the directory if nothing else is
+/// left in it.
+fn unpin_maps(bpffs: &Path) {
+    for name in PINNED_MAPS {
+        let _ = std::fs::remove_file(bpffs.join(name));
+    }
+    let _ = std::fs::remove_dir(bpffs);
+}
+
+/// Load the eBPF object. The program declares its maps pinned by name, so
+/// they always live in `pin_dir`: maps already pinned are reused, the others
+/// are created and pinned.
+fn load_bpf(pin_dir: &Path) -> Result<Bpf, anyhow::Error> {
+    std::fs::create_dir_all(pin_dir)
+        .with_context(|| format!("failed to create {}, is bpffs mounted?", pin_dir.display()))?;
+    let mut loader = BpfLoader::new();
+    loader.map_pin_path(pin_dir);
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
     #[cfg(debug_assertions)]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
+    let bpf = loader.load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/debug/github-hosts"
     ))?;
     #[cfg(not(debug_assertions))]
-    let mut bpf = Bpf::load(include_bytes_aligned!(
+    let bpf = loader.load(include_bytes_aligned!(
         "../../target/bpfel-unknown-none/release/github-hosts"
     ))?;
+    Ok(bpf)
+}
+
+/// Fail unless an instance, running or left attached by `--pin`, has its
+/// maps in `bpffs`.
+pub(crate) fn check_pinned(bpffs: &Path) -> Result<(), anyhow::Error> {
+    if !has_pins(bpffs) {
+        bail!(
+            "no maps pinned in {}, is github-hosts running?",
+            bpffs.display()
+        );
+    }
+    Ok(())
+}
+
+fn detach(opts: PinOptions) -> Result<(), anyhow::Error> {
+    check_pinned(&opts.bpffs)?;
+    // links and maps are released with their last pin
+    let entries = std::fs::read_dir(&opts.bpffs)
+        .with_context(|| format!("failed to list {}", opts.bpffs.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("link_") {
+            std::fs::remove_file(entry.path())
+                .with_context(|| format!("failed to unpin {}", entry.path().display()))?;
+        }
+    }
+    unpin_maps(&opts.bpffs);
+    Ok(())
+}
+
+fn check(opts: SourceOptions) -> Result<(), anyhow::Error> {
+    let loaded = sources::load(&opts.hosts)?;
+    let mut errors = 0;
+    for d in &loaded.diagnostics {
+        if d.diagnostic.severity == Severity::Error {
+            errors += 1;
+        }
+        println!("{}", d);
+    }
+    let mut hosts: BTreeSet<String> = loaded
+        .hosts
+        .into_values()
+        .flat_map(|h| h.into_keys())
+        .collect();
+    hosts.extend(opts.extra_rules.into_iter().map(|r| r.host));
+    for host in &hosts {
+        if let Err(e) = encode(host) {
+            errors += 1;
+            println!("{}: error: {}", host, e);
+        }
+    }
+    if errors > 0 {
+        bail!("{} errors", errors);
+    }
+    println!("{} hosts ok", hosts.len());
+    Ok(())
+}
+
+async fn run(opt: RunOptions) -> Result<(), anyhow::Error> {
+    // the kill switch, for when `ctl` is out of reach. Installed first: a
+    // SIGUSR1 during startup would otherwise terminate the process, it is
+    // handled once the loop runs
+    let mut usr1 = signal::unix::signal(SignalKind::user_defined1())?;
+
+    if opt.xdp_mode == attach::XdpMode::Offload {
+        bail!(
+            "--xdp-mode offload isn't supported: an offloaded program has to be \
+             loaded for the NIC it runs on, this one is loaded for any interface"
+        );
+    }
+
+    // before touching the pinned maps, which a running instance may share:
+    // fails if one is listening. Commands wait until the loop runs
+    let (ctl_tx, mut ctl_rx) = tokio::sync::mpsc::unbounded_channel();
+    ctl::serve(&opt.control_socket, ctl_tx)?;
+
+    // the maps are pinned in any case, and unpinned on exit unless `--pin`
+    // is given. Maps and links left pinned by an earlier run are taken over
+    let took_over = has_pins(&opt.pinning.bpffs);
+    let mut bpf = load_bpf(&opt.pinning.bpffs)?;
     if let Err(e) = BpfLogger::init(&mut bpf) {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let program: &mut Xdp = bpf.program_mut("github_hosts").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&opt.iface, XdpFlags::default())
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    let attacher = attach::Attacher {
+        hook: opt.hook,
+        xdp_mode: opt.xdp_mode,
+        tc_directions: opt.tc_direction.clone(),
+        track_queries: opt.verify_queries || opt.measure_rtt,
+        pin: opt.pin,
+        bpffs: opt.pinning.bpffs.clone(),
+    };
+    if opt.authoritative && opt.hook != attach::Hook::Xdp {
+        bail!("--authoritative needs --hook xdp");
+    }
+    attacher.load(&mut bpf)?;
+    let mut config: Array<_, u32> = Array::try_from(bpf.map_mut("CONFIG")?)?;
+    let mut flags = 0;
+    if opt.authoritative {
+        flags |= FLAG_AUTHORITATIVE;
+    }
+    if opt.verify_queries {
+        flags |= FLAG_VERIFY;
+    }
+    if opt.measure_rtt {
+        flags |= FLAG_RTT;
+    }
+    if opt.dry_run {
+        flags |= FLAG_DRY_RUN;
+    }
+    config.set(CONFIG_FLAGS, flags, 0)?;
+    events::spawn(&mut bpf)?;
+    if took_over {
+        // the client policies come from the command line alone: forget those
+        // of the previous run, in the trie the pinned program and `ctl` share
+        let pinned = pin::PinnedMap::open(&opt.pinning.bpffs.join("CLIENTS"))?;
+        for key in pinned.keys::<Key<u32>>()? {
+            pinned.remove(&key)?;
+        }
+    }
+    let clients: LpmTrie<_, u32, u32> = LpmTrie::try_from(bpf.map_mut("CLIENTS")?)?;
+    for client in &opt.clients {
+        let key = Key::new(client.prefix_len, u32::from_ne_bytes(client.addr.octets()));
+        clients.insert(&key, client.policy, 0)?;
+    }
+    let mut attached = BTreeMap::new();
+    let (link_tx, mut link_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut ifaces: Vec<String> = if opt.all {
+        netlink::spawn(link_tx)?;
+        netlink::interfaces()?
+            .into_iter()
+            .filter(|iface| iface != "lo")
+            .collect()
+    } else {
+        drop(link_tx);
+        opt.ifaces.clone()
+    };
+    if opt.loopback && !ifaces.iter().any(|iface| iface == "lo") {
+        ifaces.push("lo".to_string());
+    }
+    for iface in ifaces {
+        let link = attacher.attach(&mut bpf, &iface)?;
+        attached.insert(iface, link);
+    }
 
-    let mut github_hosts: HashMap<_, [u8; 256], [u8; 4]> =
+    let github_hosts: HashMap<_, RuleKey, RuleValue> =
         HashMap::try_from(bpf.map_mut("GITHUB_HOSTS")?)?;
+    let stats: PerCpuArray<_, u64> = PerCpuArray::try_from(bpf.map("STATS")?)?;
+    let rtt = PerCpuHashMap::try_from(bpf.map("RTT")?)?;
+
+    let mut rules = Rules::new(
+        github_hosts,
+        opt.subscriptions.len(),
+        opt.sources.hosts.clone(),
+        opt.sources.extra_rules.clone(),
+    );
+    rules.adopt_installed()?;
+    rules.reload_files()?;
 
-    let mut google = [0; 256];
-    unsafe {
-        core::ptr::copy_nonoverlapping(b"\x06google\x03com\x00".as_ptr(), google.as_mut_ptr(), 12)
+    let mut subscriptions = vec![];
+    for (index, url) in opt.subscriptions.iter().enumerate() {
+        let mut sub = Subscription::new(url, &opt.cache_dir)?;
+        if let Some(hosts) = sub.load_cache() {
+            rules.apply(rules::Update::Remote { index, hosts });
+        }
+        subscriptions.push(sub);
     }
-    github_hosts.insert(google, [1, 2, 3, 4], 0)?;
-    let mut google = [0; 256];
-    unsafe {
-        //core::ptr::copy_nonoverlapping(b"\x06github\x02io\x00".as_ptr(), google.as_mut_ptr(), 11)
-        core::ptr::copy_nonoverlapping(
-            b"\x06github\x12githubassets\x03com\x00".as_ptr(),
-            google.as_mut_ptr(),
-            25,
-        )
+    if let Err(e) = rules.sync() {
+        warn!("{:#}", e);
     }
-    github_hosts.insert(google, [4, 3, 2, 1], 0)?;
 
-    let (k, v) = gen("123.com", "4.4.4.4").unwrap();
-    github_hosts.insert(k, v, 0)?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    subscription::spawn(
+        subscriptions,
+        Duration::from_secs(opt.refresh_interval),
+        tx.clone(),
+    );
+    let prober = probe::Prober::new(
+        opt.probe.unwrap_or(probe::Mode::Connect),
+        opt.probe_port,
+        Duration::from_secs(2),
+    );
+    if opt.probe.is_some() {
+        probe::spawn(
+            prober.clone(),
+            probe::Selector::new(opt.probe_hysteresis, 2),
+            Duration::from_secs(opt.probe_interval),
+            rules.candidates(),
+            tx.clone(),
+        );
+    }
+    if opt.health_check {
+        health::spawn(
+            prober,
+            health::Tracker::new(opt.health_fall, opt.health_rise),
+            Duration::from_secs(opt.health_interval),
+            rules.candidates(),
+            tx,
+        );
+    }
 
-    let file = std::fs::File::open("github-hosts.txt").unwrap();
-    let lines = std::io::BufReader::new(file).lines();
+    // installs and removes the rules with a window on schedule
+    let mut minutes = tokio::time::interval(Duration::from_secs(60));
+    minutes.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
-    for line in lines {
-        let line = line.unwrap();
-        if line.starts_with("#") || line == "" {
-            continue;
+    info!("Waiting for Ctrl-C...");
+    loop {
+        tokio::select! {
+            r = signal::ctrl_c() => {
+                r?;
+                break;
+            }
+            Some(update) = rx.recv() => {
+                rules.apply(update);
+                if let Err(e) = rules.sync() {
+                    warn!("{:#}", e);
+                }
+            }
+            Some(event) = link_rx.recv() => match event {
+                LinkEvent::New(iface) if iface != "lo" && !attached.contains_key(&iface) => {
+                    match attacher.attach(&mut bpf, &iface) {
+                        Ok(link) => {
+                            attached.insert(iface, link);
+                        }
+                        Err(e) => warn!("failed to attach to {}: {:#}", iface, e),
+                    }
+                }
+                LinkEvent::Removed(iface) => {
+                    if let Some(link) = attached.remove(&iface) {
+                        // the kernel may have dropped the attachments with
+                        // the interface already
+                        match attacher.detach(&mut bpf, &iface, link) {
+                            Ok(()) => info!("{} went away, detached", iface),
+                            Err(e) => warn!("{} went away, failed to detach: {:#}", iface, e),
+                        }
+                    }
+                }
+                LinkEvent::New(_) => {}
+            },
+            _ = minutes.tick() => {
+                if let Err(e) = rules.sync() {
+                    warn!("{:#}", e);
+                }
+            }
+            Some(()) = usr1.recv() => {
+                let bypass = config.get(&CONFIG_FLAGS, 0)? & FLAG_BYPASS == 0;
+                let state = if bypass { ctl::Toggle::On } else { ctl::Toggle::Off };
+                ctl::set_flag(&mut config, FLAG_BYPASS, state)?;
+                if bypass {
+                    warn!("SIGUSR1: bypassing, every packet goes through untouched");
+                } else {
+                    warn!("SIGUSR1: rewriting again");
+                }
+            }
+            Some((command, reply)) = ctl_rx.recv() => {
+                let response = ctl::handle(command, &mut rules, &mut config, &stats, &rtt)
+                    .unwrap_or_else(|e| format!("error: {:#}\n", e));
+                let _ = reply.send(response);
+            }
         }
-        let mut line_split = line.split(" ").filter(|x| x != &"");
-        let ip = line_split.next().unwrap();
-        let host = line_split.next().unwrap();
-        println!("add github hosts: {}: {}", host, ip);
-        let (k, v) = gen(host, ip).unwrap();
-        github_hosts.insert(k, v, 0)?;
     }
-
-    info!("Waiting for Ctrl-C...");
-    signal::ctrl_c().await?;
     info!("Exiting...");
+    let _ = std::fs::remove_file(&opt.control_socket);
+    if opt.pin {
+        info!(
+            "leaving the program attached, `github-hosts detach` removes it from {}",
+            opt.pinning.bpffs.display()
+        );
+    } else {
+        // the maps and links go away with the last reference to them, i.e.
+        // this process once unpinned
+        for (iface, links) in attached {
+            if let Err(e) = attacher.detach(&mut bpf, &iface, links) {
+                warn!("failed to detach from {}: {:#}", iface, e);
+            }
+        }
+        unpin_maps(&opt.pinning.bpffs);
+    }
 
     Ok(())
 }
 
-// helper function for test
-fn to_256(input: &[u8]) -> [u8; 256] {
-    if input.len() >= 256 {
-        return input[0..256].try_into().unwrap();
-    }
-    let mut result = [0; 256];
-    unsafe {
-        core::ptr::copy_nonoverlapping(input.as_ptr(), result.as_mut_ptr(), input.len());
-    }
-    result
-}
-
 #[test]
-fn test_gen() {
-    to_256(b"\x06github\x0cgithubassets\x03com\x00");
+fn test_parse_client() {
     assert_eq!(
-        gen("github.githubassets.com", "185.199.108.154"),
-        Some((
-            to_256(b"\x06github\x0cgithubassets\x03com\x00"),
-            [185, 199, 108, 154]
-        ))
-    )
-}
-
-//185.199.108.154              github.githubassets.com
-//140.82.112.22                central.github.com
-//185.199.108.133              desktop.githubusercontent.com
-//185.199.108.153              assets-cdn.github.com
-fn gen(host: &str, ip: &str) -> Option<([u8; 256], [u8; 4])> {
-    let mut host_with_len = [0; 256];
-    let mut ips = [0; 4];
-    let mut i = 0usize;
-    let mut host1 = vec![];
-    for part in host.split('.') {
-        let part_len = part.len();
-        host1.push(part_len as u8);
-        host1.extend(part.as_bytes());
-    }
-    host1.push(0);
-    unsafe {
-        core::ptr::copy_nonoverlapping(host1.as_ptr(), host_with_len.as_mut_ptr(), host1.len());
-    }
-    if ip.split(".").count() != 4 {
-        return None;
-    }
-
-    i = 0;
-    for part in ip.split(".") {
-        if i >= 4 {
-            return None;
-        }
-        ips[i] = part.parse::<u8>().unwrap();
-        i += 1;
-    }
-    return Some((host_with_len, ips));
+        parse_client("10.0.8.0/24=1"),
+        Ok(ClientPolicy {
+            addr: Ipv4Addr::new(10, 0, 8, 0),
+            prefix_len: 24,
+            policy: 1,
+        })
+    );
+    assert_eq!(parse_client("10.0.8.7=1").map(|c| c.prefix_len), Ok(32));
+    assert!(parse_client("10.0.8.0/33=1").is_err());
+    assert!(parse_client("10.0.8.0/24").is_err());
 }
@@ -0,0 +1,183 @@
+//! Interface add/remove notifications from rtnetlink, for `--all`.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use log::warn;
+use tokio::sync::mpsc::UnboundedSender;
+
+const RTMGRP_LINK: u32 = 1;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const IFLA_IFNAME: u16 = 3;
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFO_LEN: usize = 16;
+const RTA_HDR_LEN: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkEvent {
+    /// An interface appeared, or changed state
+    New(String),
+    Removed(String),
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(buf.get(at..at + 2)?.try_into().unwrap()))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(at..at + 4)?.try_into().unwrap()))
+}
+
+/// `IFLA_IFNAME` of the attributes following an `ifinfomsg`.
+fn ifname(mut attrs: &[u8]) -> Option<String> {
+    while attrs.len() >= RTA_HDR_LEN {
+        let len = u16_at(attrs, 0)? as usize;
+        if len < RTA_HDR_LEN || len > attrs.len() {
+            return None;
+        }
+        if u16_at(attrs, 2)? == IFLA_IFNAME {
+            let name = &attrs[RTA_HDR_LEN..len];
+            let name = name.split(|b| *b == 0).next()?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        attrs = &attrs[align(len).min(attrs.len())..];
+    }
+    None
+}
+
+/// The link events in one datagram read from the socket.
+fn parse(mut buf: &[u8]) -> Vec<LinkEvent> {
+    let mut events = vec![];
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = match u32_at(buf, 0) {
+            Some(len) if len as usize >= NLMSG_HDR_LEN && len as usize <= buf.len() => len as usize,
+            _ => break,
+        };
+        let msg = &buf[..len];
+        let name = || msg.get(NLMSG_HDR_LEN + IFINFO_LEN..).and_then(ifname);
+        match u16_at(msg, 4) {
+            Some(RTM_NEWLINK) => events.extend(name().map(LinkEvent::New)),
+            Some(RTM_DELLINK) => events.extend(name().map(LinkEvent::Removed)),
+            _ => {}
+        }
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    events
+}
+
+fn subscribe() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups = RTMGRP_LINK;
+    let r = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// Send interface additions and removals to `tx`. Subscribe before listing
+/// the current interfaces so that none is missed in between.
+pub fn spawn(tx: UnboundedSender<LinkEvent>) -> io::Result<()> {
+    let fd = subscribe()?;
+    // a plain thread blocked in recv, it dies with the process
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let n = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    // events were dropped, the next ones still come
+                    Some(libc::ENOBUFS) => warn!("netlink: {}", e),
+                    Some(libc::EINTR) => {}
+                    _ => {
+                        warn!("netlink: {}, no longer following interfaces", e);
+                        return;
+                    }
+                }
+                continue;
+            }
+            for event in parse(&buf[..n as usize]) {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Names of the interfaces present now.
+pub fn interfaces() -> io::Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in std::fs::read_dir("/sys/class/net")? {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, name: &str) -> Vec<u8> {
+        let mut attr = vec![];
+        let attr_len = RTA_HDR_LEN + name.len() + 1;
+        attr.extend((attr_len as u16).to_ne_bytes());
+        attr.extend(IFLA_IFNAME.to_ne_bytes());
+        attr.extend(name.as_bytes());
+        attr.push(0);
+        attr.resize(align(attr_len), 0);
+
+        let len = NLMSG_HDR_LEN + IFINFO_LEN + attr.len();
+        let mut msg = vec![];
+        msg.extend((len as u32).to_ne_bytes());
+        msg.extend(kind.to_ne_bytes());
+        msg.resize(NLMSG_HDR_LEN + IFINFO_LEN, 0);
+        msg.extend(attr);
+        msg
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = message(RTM_NEWLINK, "veth0");
+        buf.extend(message(RTM_DELLINK, "wlp3s0"));
+        // not a link message
+        buf.extend(message(20, "eth0"));
+        assert_eq!(
+            parse(&buf),
+            [
+                LinkEvent::New("veth0".into()),
+                LinkEvent::Removed("wlp3s0".into())
+            ]
+        );
+        // a truncated message is dropped, not misread
+        let buf = message(RTM_NEWLINK, "veth0");
+        assert_eq!(parse(&buf[..buf.len() - 4]), []);
+    }
+}
@@ -0,0 +1,149 @@
+//! Just enough of the classic pcap format for `replay`: Ethernet captures,
+//! in either byte order, with microsecond or nanosecond timestamps. A
+//! capture is written back the way it was read.
+
+use anyhow::bail;
+
+const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const MAGIC_NANOS: u32 = 0xa1b23c4d;
+const LINKTYPE_ETHERNET: u32 = 1;
+const HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub ts_sec: u32,
+    /// Microseconds or nanoseconds, as in the file
+    pub ts_frac: u32,
+    /// Length on the wire, `data` may be shorter
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    /// The file header, kept as is
+    header: [u8; HEADER_LEN],
+    big_endian: bool,
+    pub packets: Vec<Packet>,
+}
+
+impl Capture {
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes[..4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn put_u32(&self, out: &mut Vec<u8>, n: u32) {
+        if self.big_endian {
+            out.extend(n.to_be_bytes());
+        } else {
+            out.extend(n.to_le_bytes());
+        }
+    }
+}
+
+pub fn read(bytes: &[u8]) -> Result<Capture, anyhow::Error> {
+    if bytes.len() < HEADER_LEN {
+        bail!("not a pcap file: too short");
+    }
+    let magic = bytes[..4].try_into().unwrap();
+    let big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+        (MAGIC_MICROS | MAGIC_NANOS, _) => false,
+        (_, MAGIC_MICROS | MAGIC_NANOS) => true,
+        _ => bail!("not a pcap file: bad magic, pcapng isn't supported"),
+    };
+    let mut capture = Capture {
+        header: bytes[..HEADER_LEN].try_into().unwrap(),
+        big_endian,
+        packets: vec![],
+    };
+    let linktype = capture.u32(&bytes[20..]) & 0xffff;
+    if linktype != LINKTYPE_ETHERNET {
+        bail!("link type {} isn't supported, only Ethernet", linktype);
+    }
+    let mut rest = &bytes[HEADER_LEN..];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_LEN {
+            bail!(
+                "truncated record header after {} packets",
+                capture.packets.len()
+            );
+        }
+        let incl_len = capture.u32(&rest[8..]) as usize;
+        let data = match rest.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + incl_len) {
+            Some(data) => data.to_vec(),
+            None => bail!("truncated packet after {} packets", capture.packets.len()),
+        };
+        capture.packets.push(Packet {
+            ts_sec: capture.u32(rest),
+            ts_frac: capture.u32(&rest[4..]),
+            orig_len: capture.u32(&rest[12..]),
+            data,
+        });
+        rest = &rest[RECORD_HEADER_LEN + incl_len..];
+    }
+    Ok(capture)
+}
+
+pub fn write(capture: &Capture) -> Vec<u8> {
+    let mut out = capture.header.to_vec();
+    for packet in &capture.packets {
+        capture.put_u32(&mut out, packet.ts_sec);
+        capture.put_u32(&mut out, packet.ts_frac);
+        capture.put_u32(&mut out, packet.data.len() as u32);
+        capture.put_u32(&mut out, packet.orig_len);
+        out.extend(&packet.data);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for big_endian in [false, true] {
+            let put = |n: u32| {
+                if big_endian {
+                    n.to_be_bytes()
+                } else {
+                    n.to_le_bytes()
+                }
+            };
+            let mut file = vec![];
+            file.extend(put(MAGIC_MICROS));
+            // version 2.4
+            file.extend(if big_endian {
+                [0, 2, 0, 4]
+            } else {
+                [2, 0, 4, 0]
+            });
+            file.extend([0; 8]);
+            file.extend(put(65535));
+            file.extend(put(LINKTYPE_ETHERNET));
+            for (ts, data) in [(1, &b"abc"[..]), (2, &b""[..])] {
+                file.extend(put(ts));
+                file.extend(put(500));
+                file.extend(put(data.len() as u32));
+                file.extend(put(60));
+                file.extend(data);
+            }
+
+            let capture = read(&file).unwrap();
+            assert_eq!(capture.packets.len(), 2);
+            assert_eq!(capture.packets[0].ts_sec, 1);
+            assert_eq!(capture.packets[0].orig_len, 60);
+            assert_eq!(capture.packets[0].data, b"abc");
+            assert_eq!(write(&capture), file);
+
+            assert!(read(&file[..file.len() - 20]).is_err());
+        }
+        assert!(read(b"\x0a\x0d\x0d\x0a").is_err());
+    }
+}
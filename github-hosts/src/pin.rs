@@ -0,0 +1,231 @@
+//! XDP attachment through a bpf_link pinned in bpffs, which keeps the program
+//! attached after the process exits, and access to the maps pinned next to
+//! it.
+//!
+//! aya can't pin links, nor open a pinned map without loading the program
+//! that uses it, so the few bpf(2) commands needed are issued here.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use aya::programs::XdpFlags;
+use aya::Pod;
+
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_OBJ_GET: libc::c_long = 7;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_LINK_UPDATE: libc::c_long = 29;
+
+const BPF_XDP: u32 = 37;
+
+#[repr(C)]
+struct ObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    key: u64,
+    /// Or `next_key`
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct LinkUpdateAttr {
+    link_fd: u32,
+    new_prog_fd: u32,
+    flags: u32,
+    old_prog_fd: u32,
+}
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(r)
+}
+
+fn c_path(path: &Path) -> Result<CString, anyhow::Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("invalid path {}", path.display()))
+}
+
+/// The object pinned at `path`.
+fn obj_get(path: &Path) -> io::Result<OwnedFd> {
+    let pathname = CString::new(path.as_os_str().as_bytes())?;
+    let fd = sys_bpf(
+        BPF_OBJ_GET,
+        &ObjAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        },
+    )?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Switch the link left pinned at `path` by a previous instance over to
+/// `prog_fd`. The link keeps the interface and mode it was created with.
+pub fn update_xdp(prog_fd: RawFd, path: &Path) -> Result<(), anyhow::Error> {
+    let link = obj_get(path)
+        .with_context(|| format!("failed to open the link pinned at {}", path.display()))?;
+    sys_bpf(
+        BPF_LINK_UPDATE,
+        &LinkUpdateAttr {
+            link_fd: link.as_raw_fd() as u32,
+            new_prog_fd: prog_fd as u32,
+            flags: 0,
+            old_prog_fd: 0,
+        },
+    )
+    .context("failed to replace the program of the pinned link")?;
+    Ok(())
+}
+
+/// Attach `prog_fd` to `iface` through a new link pinned at `path`.
+pub fn attach_xdp(
+    prog_fd: RawFd,
+    iface: &str,
+    flags: XdpFlags,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let pathname = c_path(path)?;
+    let name = CString::new(iface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        bail!("unknown interface {}", iface);
+    }
+    let link = sys_bpf(
+        BPF_LINK_CREATE,
+        &LinkCreateAttr {
+            prog_fd: prog_fd as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: flags.bits(),
+        },
+    )
+    .context("failed to create an XDP link, pinning needs Linux 5.9 or later")?;
+    // the pin keeps the link, closing the fd doesn't detach it
+    let link = unsafe { OwnedFd::from_raw_fd(link as RawFd) };
+    sys_bpf(
+        BPF_OBJ_PIN,
+        &ObjAttr {
+            pathname: pathname.as_ptr() as u64,
+            bpf_fd: link.as_raw_fd() as u32,
+            file_flags: 0,
+        },
+    )
+    .with_context(|| format!("failed to pin the link at {}", path.display()))?;
+    Ok(())
+}
+
+/// A map pinned in bpffs, opened by path: reading it needs no program to be
+/// loaded. Keys and values are those of the eBPF side, as for aya's maps.
+pub struct PinnedMap {
+    fd: OwnedFd,
+}
+
+impl PinnedMap {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let fd = obj_get(path)
+            .with_context(|| format!("failed to open the map pinned at {}", path.display()))?;
+        Ok(PinnedMap { fd })
+    }
+
+    fn elem<K>(&self, key: *const K, value: u64, flags: u64) -> MapElemAttr {
+        MapElemAttr {
+            map_fd: self.fd.as_raw_fd() as u32,
+            key: key as u64,
+            value,
+            flags,
+        }
+    }
+
+    /// The `len` bytes of the value of `key`, `None` if there is none.
+    fn lookup<K: Pod>(&self, key: &K, len: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut value = vec![0u8; len];
+        let attr = self.elem(key, value.as_mut_ptr() as u64, 0);
+        match sys_bpf(BPF_MAP_LOOKUP_ELEM, &attr) {
+            Ok(_) => Ok(Some(value)),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn get<K: Pod, V: Pod>(&self, key: &K) -> io::Result<Option<V>> {
+        let value = self.lookup(key, std::mem::size_of::<V>())?;
+        Ok(value.map(|v| unsafe { (v.as_ptr() as *const V).read_unaligned() }))
+    }
+
+    /// The value of `key` on every possible CPU, of a per-CPU map.
+    pub fn get_per_cpu<K: Pod, V: Pod>(&self, key: &K) -> io::Result<Option<Vec<V>>> {
+        // the kernel copies one 8 byte aligned slot per CPU
+        let slot = (std::mem::size_of::<V>() + 7) & !7;
+        let value = self.lookup(key, slot * aya::util::nr_cpus()?)?;
+        Ok(value.map(|v| {
+            v.chunks(slot)
+                .map(|c| unsafe { (c.as_ptr() as *const V).read_unaligned() })
+                .collect()
+        }))
+    }
+
+    pub fn insert<K: Pod, V: Pod>(&self, key: &K, value: &V) -> io::Result<()> {
+        let attr = self.elem(key, value as *const V as u64, 0);
+        sys_bpf(BPF_MAP_UPDATE_ELEM, &attr)?;
+        Ok(())
+    }
+
+    /// Whether there was an entry to remove.
+    pub fn remove<K: Pod>(&self, key: &K) -> io::Result<bool> {
+        let attr = self.elem(key, 0, 0);
+        match sys_bpf(BPF_MAP_DELETE_ELEM, &attr) {
+            Ok(_) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The keys of a hash map.
+    pub fn keys<K: Pod>(&self) -> io::Result<Vec<K>> {
+        let mut keys: Vec<K> = vec![];
+        loop {
+            let mut next = std::mem::MaybeUninit::<K>::uninit();
+            // no key gets the first one
+            let key = keys.last().map_or(std::ptr::null(), |k| k as *const K);
+            let attr = self.elem(key, next.as_mut_ptr() as u64, 0);
+            match sys_bpf(BPF_MAP_GET_NEXT_KEY, &attr) {
+                Ok(_) => keys.push(unsafe { next.assume_init() }),
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(keys),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
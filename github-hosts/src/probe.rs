@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc::UnboundedSender, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::rules::Update;
+use crate::sources::Hosts;
+
+/// What a probe measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// TCP connect time
+    Connect,
+    /// TCP connect plus TLS handshake, with the rule's host as SNI
+    Tls,
+}
+
+/// Probes done per candidate and round, the median is kept.
+const ATTEMPTS: usize = 3;
+
+/// Measures how long it takes to reach a candidate IP.
+#[derive(Clone)]
+pub struct Prober {
+    port: u16,
+    timeout: Duration,
+    tls: Option<TlsConnector>,
+}
+
+impl Prober {
+    pub fn new(mode: Mode, port: u16, timeout: Duration) -> Self {
+        let tls = match mode {
+            Mode::Connect => None,
+            Mode::Tls => {
+                let mut roots = RootCertStore::empty();
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+                let config = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                Some(TlsConnector::from(Arc::new(config)))
+            }
+        };
+        Prober { port, timeout, tls }
+    }
+
+    /// One attempt, `None` if `ip` couldn't be reached within the timeout.
+    pub async fn measure(&self, host: &str, ip: Ipv4Addr) -> Option<Duration> {
+        let start = Instant::now();
+        let addr = SocketAddr::from((ip, self.port));
+        let probe = async {
+            let stream = TcpStream::connect(addr).await?;
+            if let Some(tls) = &self.tls {
+                let name = ServerName::try_from(host)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                tls.connect(name, stream).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        match tokio::time::timeout(self.timeout, probe).await {
+            Ok(Ok(())) => Some(start.elapsed()),
+            Ok(Err(e)) => {
+                debug!("probe {} {}: {}", host, addr, e);
+                None
+            }
+            Err(_) => {
+                debug!("probe {} {}: timed out", host, addr);
+                None
+            }
+        }
+    }
+
+    /// Median of [`ATTEMPTS`] attempts, `None` if none succeeded.
+    pub async fn sample(&self, host: &str, ip: Ipv4Addr) -> Option<Duration> {
+        let mut samples = vec![];
+        for _ in 0..ATTEMPTS {
+            if let Some(d) = self.measure(host, ip).await {
+                samples.push(d);
+            }
+        }
+        samples.sort();
+        samples.get(samples.len() / 2).copied()
+    }
+}
+
+/// Picks the fastest candidate per host, with hysteresis: a reachable current
+/// choice is only replaced by a candidate that is faster by more than
+/// `margin` (a fraction of the current latency) for `rounds` rounds in a row.
+/// An unreachable current choice is replaced right away.
+pub struct Selector {
+    margin: f64,
+    rounds: u32,
+    current: HashMap<String, Ipv4Addr>,
+    /// host -> (challenger, consecutive rounds it won)
+    challengers: HashMap<String, (Ipv4Addr, u32)>,
+}
+
+impl Selector {
+    pub fn new(margin: f64, rounds: u32) -> Self {
+        Selector {
+            margin,
+            rounds,
+            current: HashMap::new(),
+            challengers: HashMap::new(),
+        }
+    }
+
+    /// Feed one round of results for `host`, returns the new choice if it
+    /// changed. Until a switch, the choice is the first candidate.
+    pub fn select(
+        &mut self,
+        host: &str,
+        candidates: &[Ipv4Addr],
+        results: &[(Ipv4Addr, Option<Duration>)],
+    ) -> Option<Ipv4Addr> {
+        let current = match self.current.get(host) {
+            Some(ip) if candidates.contains(ip) => *ip,
+            _ => {
+                let first = *candidates.first()?;
+                self.current.insert(host.to_string(), first);
+                self.challengers.remove(host);
+                first
+            }
+        };
+        let latency = |ip: Ipv4Addr| results.iter().find(|(i, _)| *i == ip).and_then(|r| r.1);
+        let (best, best_latency) = results
+            .iter()
+            .filter_map(|(ip, d)| d.map(|d| (*ip, d)))
+            .min_by_key(|(_, d)| *d)?;
+        if best == current {
+            self.challengers.remove(host);
+            return None;
+        }
+
+        let won = match latency(current) {
+            None => self.rounds,
+            Some(d) if best_latency.as_secs_f64() < d.as_secs_f64() * (1.0 - self.margin) => {
+                match self.challengers.get(host) {
+                    Some((ip, n)) if *ip == best => n + 1,
+                    _ => 1,
+                }
+            }
+            Some(_) => {
+                self.challengers.remove(host);
+                return None;
+            }
+        };
+        if won < self.rounds {
+            self.challengers.insert(host.to_string(), (best, won));
+            return None;
+        }
+        info!(
+            "{}: switching to {} ({:?}), was {} ({:?})",
+            host,
+            best,
+            best_latency,
+            current,
+            latency(current)
+        );
+        self.challengers.remove(host);
+        self.current.insert(host.to_string(), best);
+        Some(best)
+    }
+}
+
+/// Probe every host with more than one candidate each `interval`, sending
+/// the new choice to `tx` whenever the ranking changes.
+pub fn spawn(
+    prober: Prober,
+    mut selector: Selector,
+    interval: Duration,
+    candidates: watch::Receiver<Hosts>,
+    tx: UnboundedSender<Update>,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let hosts = candidates.borrow().clone();
+            for (host, ips) in hosts.into_iter().filter(|(_, ips)| ips.len() > 1) {
+                let mut probes = JoinSet::new();
+                for ip in ips.iter().copied() {
+                    let prober = prober.clone();
+                    let host = host.clone();
+                    probes.spawn(async move { (ip, prober.sample(&host, ip).await) });
+                }
+                let mut results = vec![];
+                while let Some(r) = probes.join_next().await {
+                    results.push(r.expect("probe task panicked"));
+                }
+                debug!("{}: {:?}", host, results);
+                if let Some(ip) = selector.select(&host, &ips, &results) {
+                    if tx.send(Update::Preferred { host, ip }).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn test_select_hysteresis() {
+        let a = Ipv4Addr::new(1, 1, 1, 1);
+        let b = Ipv4Addr::new(2, 2, 2, 2);
+        let mut s = Selector::new(0.2, 2);
+
+        // a is current, b is only slightly faster
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(100)), (b, ms(90))]), None);
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(100)), (b, ms(90))]), None);
+        // b is much faster, but only once
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(100)), (b, ms(50))]), None);
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(100)), (b, ms(95))]), None);
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(100)), (b, ms(50))]), None);
+        // second round in a row
+        assert_eq!(
+            s.select("h", &[a, b], &[(a, ms(100)), (b, ms(50))]),
+            Some(b)
+        );
+        // a is back to being a bit faster, no flapping
+        assert_eq!(s.select("h", &[a, b], &[(a, ms(45)), (b, ms(50))]), None);
+    }
+
+    #[test]
+    fn test_select_unreachable() {
+        let a = Ipv4Addr::new(1, 1, 1, 1);
+        let b = Ipv4Addr::new(2, 2, 2, 2);
+        let mut s = Selector::new(0.2, 2);
+
+        // current down: switch at once
+        assert_eq!(s.select("h", &[a, b], &[(a, None), (b, ms(300))]), Some(b));
+        // everything down: keep the current choice
+        assert_eq!(s.select("h", &[a, b], &[(a, None), (b, None)]), None);
+        // current no longer a candidate: back to the first one
+        let c = Ipv4Addr::new(3, 3, 3, 3);
+        assert_eq!(s.select("h", &[a, c], &[(a, ms(10)), (c, ms(100))]), None);
+    }
+
+    #[tokio::test]
+    async fn test_measure() {
+        // a local listener stands in for a GitHub edge, nothing listens on
+        // the same port of 127.0.0.2
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let prober = Prober::new(Mode::Connect, port, Duration::from_secs(1));
+
+        assert!(prober
+            .sample("github.com", Ipv4Addr::new(127, 0, 0, 1))
+            .await
+            .is_some());
+        assert!(prober
+            .measure("github.com", Ipv4Addr::new(127, 0, 0, 2))
+            .await
+            .is_none());
+    }
+}
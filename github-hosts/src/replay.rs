@@ -0,0 +1,223 @@
+//! `replay`: the rewrite of the eBPF program, run by the same code from
+//! `github_hosts_common::dns` over the packets of a capture, to see what the
+//! rules would do to real traffic without attaching anything.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use anyhow::Context;
+
+use github_hosts_common::dns;
+use github_hosts_common::name::show;
+use github_hosts_common::{Action, RuleKey, RuleValue, DEFAULT_POLICY};
+
+use crate::rules::{local_minute, Rules};
+use crate::{pcap, ClientPolicy, ReplayOptions};
+
+/// The rules as the eBPF program sees them: `GITHUB_HOSTS` and `CLIENTS`.
+struct Tables {
+    rules: HashMap<RuleKey, RuleValue>,
+    clients: Vec<ClientPolicy>,
+}
+
+impl Tables {
+    /// Policy of the longest prefix `client` is in, as the LPM trie lookup.
+    fn policy(&self, client: Ipv4Addr) -> Option<u32> {
+        self.clients
+            .iter()
+            .filter(|c| {
+                let mask = u32::MAX.checked_shl(32 - c.prefix_len).unwrap_or(0);
+                u32::from(client) & mask == u32::from(c.addr) & mask
+            })
+            .max_by_key(|c| c.prefix_len)
+            .map(|c| c.policy)
+    }
+
+    /// The rule for the question of `key`, falling back to the default
+    /// policy like `lookup` in the eBPF program.
+    fn lookup(&self, key: &mut RuleKey, client: Ipv4Addr) -> Option<RuleValue> {
+        if let Some(policy) = self.policy(client) {
+            key.policy = policy;
+            if let Some(rule) = self.rules.get(key) {
+                return Some(*rule);
+            }
+            key.policy = DEFAULT_POLICY;
+        }
+        self.rules.get(key).copied()
+    }
+}
+
+/// What was done to a response.
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    key: RuleKey,
+    client: Ipv4Addr,
+    rule: RuleValue,
+    /// Address of the first A record before the rewrite
+    old: [u8; 4],
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", show(&self.key.name))?;
+        if self.key.policy != DEFAULT_POLICY {
+            write!(f, " (policy {})", self.key.policy)?;
+        }
+        write!(f, " for {}: ", self.client)?;
+        match self.rule.action() {
+            Some(Action::Nxdomain) => write!(f, "answered NXDOMAIN"),
+            _ => write!(f, "rewrote {} to {}", Ipv4Addr::from(self.old), self.rule),
+        }
+    }
+}
+
+/// Apply the rules to one frame, as `try_github_hosts` does, without the
+/// query tracking of `--verify-queries`.
+fn rewrite(tables: &Tables, data: &mut Vec<u8>) -> Option<Change> {
+    let b = &mut data[..];
+    let udp = dns::udp(b)?;
+    if udp.source != 53 {
+        return None;
+    }
+    let header = dns::header(b)?;
+    if header.qcount != 1 {
+        return None;
+    }
+    let q_len = dns::name_len(b, dns::QUESTION_OFF)?;
+    let mut key = dns::question_key(b, q_len)?;
+    let client = Ipv4Addr::from(udp.daddr.to_ne_bytes());
+    let rule = tables.lookup(&mut key, client)?;
+    let old = match rule.action() {
+        Some(Action::Rewrite | Action::Sinkhole) if header.acount > 0 => {
+            dns::rewrite_a(b, q_len, header.acount, rule.addr, false)?
+        }
+        Some(Action::Nxdomain) => {
+            let len = dns::nxdomain(b, q_len)?;
+            data.truncate(len);
+            [0; 4]
+        }
+        _ => return None,
+    };
+    Some(Change {
+        key,
+        client,
+        rule,
+        old,
+    })
+}
+
+pub fn replay(opts: ReplayOptions) -> Result<(), anyhow::Error> {
+    let mut rules = Rules::new((), 0, opts.sources.hosts, opts.sources.extra_rules);
+    rules.reload_files()?;
+    let mut tables = Tables {
+        rules: HashMap::new(),
+        clients: opts.clients,
+    };
+    // windows are matched against when each packet was captured
+    let mut minute = None;
+
+    let input =
+        std::fs::read(&opts.input).with_context(|| format!("reading {}", opts.input.display()))?;
+    let mut capture =
+        pcap::read(&input).with_context(|| format!("reading {}", opts.input.display()))?;
+    let (mut rewritten, mut blocked) = (0, 0);
+    for (i, packet) in capture.packets.iter_mut().enumerate() {
+        let at = local_minute(packet.ts_sec as libc::time_t);
+        if minute != Some(at) {
+            tables.rules.clear();
+            for ((policy, host), rule) in rules.active_at(at) {
+                let key = RuleKey::from_host(policy, &host).with_context(|| host.clone())?;
+                tables.rules.insert(key, rule);
+            }
+            minute = Some(at);
+        }
+        let len = packet.data.len();
+        if let Some(change) = rewrite(&tables, &mut packet.data) {
+            // the frame only gets shorter, by as much on the wire
+            packet.orig_len = packet
+                .orig_len
+                .saturating_sub((len - packet.data.len()) as u32);
+            match change.rule.action() {
+                Some(Action::Rewrite) => rewritten += 1,
+                _ => blocked += 1,
+            }
+            println!("#{} {}", i + 1, change);
+        }
+    }
+    println!(
+        "{} packets, {} rewritten, {} blocked",
+        capture.packets.len(),
+        rewritten,
+        blocked
+    );
+    std::fs::write(&opts.output, pcap::write(&capture))
+        .with_context(|| format!("writing {}", opts.output.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns::response;
+
+    #[test]
+    fn test_rewrite() {
+        let key = |policy, host| RuleKey::from_host(policy, host).unwrap();
+        let tables = Tables {
+            rules: HashMap::from([
+                (
+                    key(DEFAULT_POLICY, "github.com"),
+                    "140.82.113.4".parse().unwrap(),
+                ),
+                (key(1, "github.com"), "140.82.114.4".parse().unwrap()),
+                (
+                    key(DEFAULT_POLICY, "ads.example"),
+                    "nxdomain".parse().unwrap(),
+                ),
+            ]),
+            clients: vec![ClientPolicy {
+                addr: Ipv4Addr::new(10, 0, 8, 0),
+                prefix_len: 24,
+                policy: 1,
+            }],
+        };
+
+        let mut frame = response(
+            b"\x06github\x03com\x00",
+            [10, 0, 0, 2],
+            &[[20, 205, 243, 166]],
+        );
+        let change = rewrite(&tables, &mut frame).unwrap();
+        assert_eq!(
+            change.to_string(),
+            "github.com for 10.0.0.2: rewrote 20.205.243.166 to 140.82.113.4"
+        );
+        assert_eq!(frame[frame.len() - 4..], [140, 82, 113, 4]);
+
+        let mut frame = response(
+            b"\x06github\x03com\x00",
+            [10, 0, 8, 5],
+            &[[20, 205, 243, 166]],
+        );
+        let change = rewrite(&tables, &mut frame).unwrap();
+        assert_eq!(
+            change.to_string(),
+            "github.com (policy 1) for 10.0.8.5: rewrote 20.205.243.166 to 140.82.114.4"
+        );
+
+        let mut frame = response(b"\x03ads\x07example\x00", [10, 0, 0, 2], &[[1, 2, 3, 4]]);
+        let len = frame.len();
+        let change = rewrite(&tables, &mut frame).unwrap();
+        assert_eq!(
+            change.to_string(),
+            "ads.example for 10.0.0.2: answered NXDOMAIN"
+        );
+        // the answer is cut off
+        assert_eq!(frame.len(), len - 16);
+
+        let mut frame = response(b"\x06gitlab\x03com\x00", [10, 0, 0, 2], &[[1, 2, 3, 4]]);
+        let before = frame.clone();
+        assert_eq!(rewrite(&tables, &mut frame), None);
+        assert_eq!(frame, before);
+    }
+}
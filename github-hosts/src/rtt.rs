@@ -0,0 +1,81 @@
+//! Response time histograms of the resolvers, kept by the eBPF program in the
+//! `RTT` map with `--measure-rtt`.
+
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use github_hosts_common::{RttHistogram, RTT_BUCKETS};
+
+/// Upper bound of the bucket the `p` quantile falls in, `None` for the last,
+/// unbounded bucket or an empty histogram.
+fn quantile(buckets: &[u64; RTT_BUCKETS], p: f64) -> Option<Duration> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = (total as f64 * p).ceil().max(1.0) as u64;
+    let mut seen = 0;
+    for (i, n) in buckets.iter().enumerate() {
+        seen += n;
+        if seen >= rank {
+            return if i + 1 < RTT_BUCKETS {
+                Some(Duration::from_micros(1 << i))
+            } else {
+                None
+            };
+        }
+    }
+    None
+}
+
+fn format_quantile(buckets: &[u64; RTT_BUCKETS], p: f64) -> String {
+    match quantile(buckets, p) {
+        Some(d) => format!("< {:?}", d),
+        None => format!(">= {:?}", Duration::from_micros(1 << (RTT_BUCKETS - 2))),
+    }
+}
+
+/// One line per resolver, its histogram summed over the CPUs, from the
+/// entries of the `RTT` map.
+pub fn format(rtt: Vec<(u32, Vec<RttHistogram>)>) -> String {
+    let mut out = String::new();
+    for (resolver, per_cpu) in rtt {
+        let mut buckets = [0; RTT_BUCKETS];
+        for histogram in per_cpu.iter() {
+            for (sum, n) in buckets.iter_mut().zip(histogram.buckets) {
+                *sum += n;
+            }
+        }
+        out += &format!(
+            "{}: {} responses, p50 {}, p90 {}, p99 {}\n",
+            Ipv4Addr::from(u32::from_be(resolver)),
+            buckets.iter().sum::<u64>(),
+            format_quantile(&buckets, 0.5),
+            format_quantile(&buckets, 0.9),
+            format_quantile(&buckets, 0.99),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile() {
+        let mut buckets = [0; RTT_BUCKETS];
+        assert_eq!(quantile(&buckets, 0.5), None);
+
+        // 8ms-16ms and 16ms-32ms
+        buckets[14] = 90;
+        buckets[15] = 10;
+        assert_eq!(quantile(&buckets, 0.5), Some(Duration::from_micros(16384)));
+        assert_eq!(quantile(&buckets, 0.9), Some(Duration::from_micros(16384)));
+        assert_eq!(quantile(&buckets, 0.99), Some(Duration::from_micros(32768)));
+
+        buckets[RTT_BUCKETS - 1] = 1000;
+        assert_eq!(quantile(&buckets, 0.5), None);
+        assert!(format_quantile(&buckets, 0.5).starts_with(">= "));
+    }
+}
@@ -0,0 +1,553 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use aya::maps::{HashMap, MapRefMut};
+use log::{error, warn};
+use tokio::sync::watch;
+
+use github_hosts_common::hosts::{Severity, Window};
+use github_hosts_common::name::{decode, normalize};
+use github_hosts_common::rule::Rule;
+use github_hosts_common::{Action, RuleKey, RuleValue, DEFAULT_POLICY};
+
+use crate::sources::{self, Hosts, Listed, Policies};
+
+/// A change produced by a background task, applied by the task owning [`Rules`].
+#[derive(Debug)]
+pub enum Update {
+    /// New content of the subscription at `index`
+    Remote { index: usize, hosts: Policies },
+    /// The prober found `ip` to be the best candidate for `host`
+    Preferred { host: String, ip: Ipv4Addr },
+    /// The health checker found `ip` of `host` going down or coming back up
+    Health {
+        host: String,
+        ip: Ipv4Addr,
+        up: bool,
+    },
+}
+
+/// A host as seen by the clients of a policy: `(policy, host)`.
+pub type Scoped = (u32, String);
+
+/// What the winning layer says about a host.
+enum Target {
+    /// Rewrite to the best of these
+    Candidates(Vec<Ipv4Addr>),
+    /// Blocked or passed, no candidate to choose from
+    Fixed(RuleValue),
+}
+
+impl Target {
+    /// A `0.0.0.0` candidate blocks the host, as in the usual blocklists.
+    fn from_candidates(ips: &[Ipv4Addr]) -> Self {
+        match ips.iter().find(|ip| ip.is_unspecified()) {
+            Some(ip) => Target::Fixed(RuleValue::from(*ip)),
+            None => Target::Candidates(ips.to_vec()),
+        }
+    }
+
+    fn from_rule(rule: RuleValue) -> Self {
+        match rule.action() {
+            Some(Action::Rewrite) => Target::Candidates(vec![Ipv4Addr::from(rule.addr)]),
+            _ => Target::Fixed(rule),
+        }
+    }
+}
+
+/// The rule set and the `GITHUB_HOSTS` map it is installed into.
+///
+/// Rules come from three layers, later layers overriding earlier ones for
+/// the same host and policy: remote subscriptions (in the order they were
+/// given), local hosts files and `--extra-rule`s. Rules added or removed
+/// through the control socket override all of them until restart. Of the
+/// candidate ips a host has in the winning layer and that are not down, the
+/// one preferred by the prober is used, or else the first. A host with every candidate down gets no rule, so
+/// the real answer from upstream goes through. A host listed with `0.0.0.0`
+/// is sinkholed instead, and an `nxdomain` or `pass` rule is installed as
+/// is. A rule listed with an `#@active` window, or given one on the command
+/// line, only applies during that window of the day: outside of it, the
+/// rule of the latest earlier layer does, if any. [`Rules::sync`] diffs the
+/// result against what is in the map, so any of these can change at runtime,
+/// and is called every minute to follow the windows.
+///
+/// The prober and the health checker work on hosts, whatever their policy:
+/// a host's candidates are those it has in any policy.
+///
+/// `replay` only needs [`Rules::active`], and does without the map.
+pub struct Rules<M = HashMap<MapRefMut, RuleKey, RuleValue>> {
+    map: M,
+    remote: Vec<Policies>,
+    paths: Vec<PathBuf>,
+    files: Policies,
+    extra: Policies,
+    /// `nxdomain` and `pass` `--extra-rule`s, which have no address
+    extra_fixed: BTreeMap<Scoped, (RuleValue, Option<Window>)>,
+    /// rule added at runtime, or `None` if removed
+    overrides: BTreeMap<Scoped, Option<RuleValue>>,
+    preferred: BTreeMap<String, Ipv4Addr>,
+    down: BTreeSet<(String, Ipv4Addr)>,
+    installed: BTreeMap<Scoped, RuleValue>,
+    candidates: watch::Sender<Hosts>,
+}
+
+impl<M> Rules<M> {
+    pub fn new(map: M, subscriptions: usize, paths: Vec<PathBuf>, extra_rules: Vec<Rule>) -> Self {
+        let mut extra = Policies::new();
+        let mut extra_fixed = BTreeMap::new();
+        for Rule {
+            policy,
+            host,
+            rule,
+            window,
+        } in extra_rules
+        {
+            let host = normalize(&host);
+            match rule.action() {
+                Some(Action::Rewrite | Action::Sinkhole) => {
+                    let listed: &mut Listed =
+                        extra.entry(policy).or_default().entry(host).or_default();
+                    listed.ips.push(Ipv4Addr::from(rule.addr));
+                    // the last window given for a host wins
+                    if window.is_some() {
+                        listed.window = window;
+                    }
+                }
+                _ => {
+                    extra_fixed.insert((policy, host), (rule, window));
+                }
+            }
+        }
+        Rules {
+            map,
+            remote: vec![Policies::new(); subscriptions],
+            paths,
+            files: Policies::new(),
+            extra,
+            extra_fixed,
+            overrides: BTreeMap::new(),
+            preferred: BTreeMap::new(),
+            down: BTreeSet::new(),
+            installed: BTreeMap::new(),
+            candidates: watch::channel(Hosts::new()).0,
+        }
+    }
+
+    /// Follow the candidates of every host, as of the last [`Rules::sync`].
+    pub fn candidates(&self) -> watch::Receiver<Hosts> {
+        self.candidates.subscribe()
+    }
+
+    /// Re-read the local hosts files, reporting their diagnostics.
+    pub fn reload_files(&mut self) -> Result<(), anyhow::Error> {
+        let loaded = sources::load(&self.paths)?;
+        for d in &loaded.diagnostics {
+            match d.diagnostic.severity {
+                Severity::Warning => warn!("{}", d),
+                Severity::Error => error!("{}", d),
+            }
+        }
+        self.files = normalized(loaded.hosts);
+        Ok(())
+    }
+
+    pub fn set_override(&mut self, policy: u32, host: &str, rule: Option<RuleValue>) {
+        self.overrides.insert((policy, normalize(host)), rule);
+    }
+
+    pub fn installed(&self) -> &BTreeMap<Scoped, RuleValue> {
+        &self.installed
+    }
+
+    pub fn is_down(&self, host: &str, ip: Ipv4Addr) -> bool {
+        self.down.contains(&(host.to_string(), ip))
+    }
+
+    pub fn apply(&mut self, update: Update) {
+        match update {
+            Update::Remote { index, hosts } => self.remote[index] = normalized(hosts),
+            Update::Preferred { host, ip } => {
+                self.preferred.insert(host, ip);
+            }
+            Update::Health { host, ip, up: true } => {
+                self.down.remove(&(host, ip));
+            }
+            Update::Health {
+                host,
+                ip,
+                up: false,
+            } => {
+                self.down.insert((host, ip));
+            }
+        }
+    }
+
+    /// What every layer says about each host, with its window, earliest
+    /// first. See [`Rules`] for the precedence.
+    fn merged(&self) -> BTreeMap<Scoped, Vec<(Target, Option<Window>)>> {
+        let mut merged: BTreeMap<Scoped, Vec<_>> = BTreeMap::new();
+        for layer in self.remote.iter().chain([&self.files, &self.extra]) {
+            for (policy, hosts) in layer {
+                for (host, listed) in hosts {
+                    merged
+                        .entry((*policy, host.clone()))
+                        .or_default()
+                        .push((Target::from_candidates(&listed.ips), listed.window));
+                }
+            }
+        }
+        for (scoped, (rule, window)) in &self.extra_fixed {
+            merged
+                .entry(scoped.clone())
+                .or_default()
+                .push((Target::Fixed(*rule), *window));
+        }
+        // runtime rules apply all day, and hide the other layers
+        for (scoped, rule) in &self.overrides {
+            match rule {
+                Some(rule) => merged.insert(scoped.clone(), vec![(Target::from_rule(*rule), None)]),
+                None => merged.remove(scoped),
+            };
+        }
+        merged
+    }
+
+    /// The merged candidates of the hosts that are rewritten, those of every
+    /// policy and layer together, the ones the prober and the health checker
+    /// look at.
+    pub fn desired(&self) -> Hosts {
+        let mut hosts = Hosts::new();
+        for ((_, host), layers) in self.merged() {
+            for (target, _) in layers {
+                if let Target::Candidates(ips) = target {
+                    let candidates: &mut Vec<Ipv4Addr> = hosts.entry(host.clone()).or_default();
+                    for ip in ips {
+                        if !candidates.contains(&ip) {
+                            candidates.push(ip);
+                        }
+                    }
+                }
+            }
+        }
+        hosts
+    }
+
+    /// The rule `target` makes for `host`, `None` if its candidates are all
+    /// down.
+    fn resolve(&self, host: &str, target: Target) -> Option<RuleValue> {
+        let ips = match target {
+            Target::Candidates(ips) => ips,
+            Target::Fixed(rule) => return Some(rule),
+        };
+        let up: Vec<Ipv4Addr> = ips
+            .into_iter()
+            .filter(|ip| !self.is_down(host, *ip))
+            .collect();
+        let ip = match self.preferred.get(host) {
+            Some(ip) if up.contains(ip) => *ip,
+            _ => *up.first()?,
+        };
+        Some(RuleValue::from(ip))
+    }
+
+    /// The rule of each host and policy at `minute`, see [`local_minute`],
+    /// with its window: that of the latest layer whose window includes the
+    /// minute, if any.
+    fn effective_at(&self, minute: u16) -> BTreeMap<Scoped, (RuleValue, Option<Window>)> {
+        self.merged()
+            .into_iter()
+            .filter_map(|(scoped, layers)| {
+                let (target, window) =
+                    layers.into_iter().rev().find(|(_, window)| match window {
+                        Some(window) => window.contains(minute),
+                        None => true,
+                    })?;
+                let rule = self.resolve(&scoped.1, target)?;
+                Some((scoped, (rule, window)))
+            })
+            .collect()
+    }
+
+    /// The rule in effect for each host and policy at the current local
+    /// time, with its window.
+    pub fn effective(&self) -> BTreeMap<Scoped, (RuleValue, Option<Window>)> {
+        self.effective_at(now_minute())
+    }
+
+    /// The rule to install for each host and policy, at the current local time.
+    pub fn active(&self) -> BTreeMap<Scoped, RuleValue> {
+        self.active_at(now_minute())
+    }
+
+    /// The rules in effect at `minute`, see [`local_minute`].
+    pub fn active_at(&self, minute: u16) -> BTreeMap<Scoped, RuleValue> {
+        self.effective_at(minute)
+            .into_iter()
+            .map(|(scoped, (rule, _))| (scoped, rule))
+            .collect()
+    }
+
+    /// The rules that only apply during a window of the day, in effect or
+    /// not, of every layer that isn't hidden all day by a later one.
+    pub fn scheduled(&self) -> Vec<(Scoped, RuleValue, Window)> {
+        let mut scheduled = vec![];
+        for (scoped, layers) in self.merged() {
+            for (target, window) in layers.into_iter().rev() {
+                let window = match window {
+                    Some(window) => window,
+                    None => break,
+                };
+                if let Some(rule) = self.resolve(&scoped.1, target) {
+                    scheduled.push((scoped.clone(), rule, window));
+                }
+            }
+        }
+        scheduled
+    }
+}
+
+impl Rules {
+    /// Take over the entries already in the map, left there by an instance
+    /// that didn't exit cleanly, so that [`Rules::sync`] removes the stale ones.
+    pub fn adopt_installed(&mut self) -> Result<(), anyhow::Error> {
+        let mut junk = vec![];
+        for item in self.map.iter() {
+            let (k, v) = item?;
+            match decode(&k.name) {
+                Ok(host) => {
+                    self.installed.insert((k.policy, host), v);
+                }
+                Err(_) => junk.push(k),
+            }
+        }
+        for k in junk {
+            self.map.remove(&k)?;
+        }
+        Ok(())
+    }
+
+    /// Bring `GITHUB_HOSTS` in line with [`Rules::active`]. An entry the map
+    /// refuses, e.g. once it is full, is logged and skipped, and tried again
+    /// on the next sync; the error only tells how many failed.
+    pub fn sync(&mut self) -> Result<(), anyhow::Error> {
+        self.candidates.send_replace(self.desired());
+        let desired = self.active();
+        let mut failed = 0;
+
+        let stale: Vec<Scoped> = self
+            .installed
+            .keys()
+            .filter(|scoped| !desired.contains_key(*scoped))
+            .cloned()
+            .collect();
+        for scoped in stale {
+            let (policy, host) = &scoped;
+            // installed hosts are known to encode
+            if let Err(e) = self.map.remove(&RuleKey::from_host(*policy, host)?) {
+                error!("failed to remove {}: {}", show(&scoped), e);
+                failed += 1;
+                continue;
+            }
+            let rule = self.installed.remove(&scoped).unwrap();
+            println!("remove github hosts: {}: {}", show(&scoped), rule);
+        }
+
+        for (scoped, rule) in desired {
+            if self.installed.get(&scoped) == Some(&rule) {
+                continue;
+            }
+            let k = match RuleKey::from_host(scoped.0, &scoped.1) {
+                Ok(k) => k,
+                Err(e) => {
+                    error!("skipping {}: {}", scoped.1, e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.map.insert(k, rule, 0) {
+                error!("failed to add {}: {}", show(&scoped), e);
+                failed += 1;
+                continue;
+            }
+            println!("add github hosts: {}: {}", show(&scoped), rule);
+            self.installed.insert(scoped, rule);
+        }
+        if failed > 0 {
+            bail!("{} rules could not be synced to GITHUB_HOSTS", failed);
+        }
+        Ok(())
+    }
+}
+
+/// The host, followed by its policy unless it's the default one.
+fn show((policy, host): &Scoped) -> String {
+    if *policy == DEFAULT_POLICY {
+        host.clone()
+    } else {
+        format!("{} (policy {})", host, policy)
+    }
+}
+
+/// `hosts` keyed by their [`normalize`]d names, the way
+/// [`Rules::adopt_installed`] finds them in the map, so that a restart
+/// doesn't reinstall every rule.
+fn normalized(hosts: Policies) -> Policies {
+    hosts
+        .into_iter()
+        .map(|(policy, hosts)| {
+            let hosts = hosts
+                .into_iter()
+                .map(|(host, listed)| (normalize(&host), listed))
+                .collect();
+            (policy, hosts)
+        })
+        .collect()
+}
+
+/// Minutes since midnight, local time, now.
+fn now_minute() -> u16 {
+    local_minute(unsafe { libc::time(std::ptr::null_mut()) })
+}
+
+/// Minutes since midnight, local time, at `time` in seconds since the epoch.
+pub fn local_minute(time: libc::time_t) -> u16 {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::localtime_r(&time, &mut tm);
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use github_hosts_common::hosts;
+
+    /// Rules from a hosts file and `--extra-rule`s, without a map.
+    fn rules(file: &str, extra: &[&str]) -> Rules<()> {
+        let extra = extra.iter().map(|r| r.parse().unwrap()).collect();
+        let mut rules = Rules::new((), 0, vec![], extra);
+        rules.files = normalized(sources::from_entries(
+            hosts::parse(file).entries,
+            &mut vec![],
+        ));
+        rules
+    }
+
+    fn ip(rules: &BTreeMap<Scoped, RuleValue>, host: &str) -> Option<String> {
+        rules
+            .get(&(DEFAULT_POLICY, host.to_string()))
+            .map(|r| r.to_string())
+    }
+
+    #[test]
+    fn test_windows() {
+        let rules = rules(
+            "140.82.113.4 github.com\n",
+            &[
+                "github.com=140.82.114.4@19:00-23:30",
+                "codeload.github.com=140.82.113.9@22:30-06:00",
+            ],
+        );
+        let at = |h: u16, m: u16| rules.active_at(h * 60 + m);
+
+        // outside of its window, the rule of the file applies again
+        assert_eq!(ip(&at(12, 0), "github.com").unwrap(), "140.82.113.4");
+        assert_eq!(ip(&at(19, 0), "github.com").unwrap(), "140.82.114.4");
+        assert_eq!(ip(&at(23, 30), "github.com").unwrap(), "140.82.113.4");
+
+        // across midnight
+        assert_eq!(ip(&at(12, 0), "codeload.github.com"), None);
+        assert!(ip(&at(23, 0), "codeload.github.com").is_some());
+        assert!(ip(&at(3, 0), "codeload.github.com").is_some());
+        assert_eq!(ip(&at(6, 0), "codeload.github.com"), None);
+
+        let scheduled: Vec<String> = rules
+            .scheduled()
+            .into_iter()
+            .map(|((_, host), _, _)| host)
+            .collect();
+        assert_eq!(scheduled, ["codeload.github.com", "github.com"]);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut rules = rules(
+            "#@candidates on\n\
+             140.82.113.3 github.com\n\
+             140.82.113.4 github.com\n",
+            &[],
+        );
+        let host = "github.com".to_string();
+        let first: Ipv4Addr = "140.82.113.3".parse().unwrap();
+        let second: Ipv4Addr = "140.82.113.4".parse().unwrap();
+        assert_eq!(
+            ip(&rules.active_at(0), "github.com").unwrap(),
+            "140.82.113.3"
+        );
+
+        rules.apply(Update::Preferred {
+            host: host.clone(),
+            ip: second,
+        });
+        assert_eq!(
+            ip(&rules.active_at(0), "github.com").unwrap(),
+            "140.82.113.4"
+        );
+
+        // the next candidate that is up, when the preferred one goes down
+        rules.apply(Update::Health {
+            host: host.clone(),
+            ip: second,
+            up: false,
+        });
+        assert_eq!(
+            ip(&rules.active_at(0), "github.com").unwrap(),
+            "140.82.113.3"
+        );
+
+        // no rule at all when they all are
+        rules.apply(Update::Health {
+            host: host.clone(),
+            ip: first,
+            up: false,
+        });
+        assert_eq!(ip(&rules.active_at(0), "github.com"), None);
+
+        rules.apply(Update::Health {
+            host,
+            ip: second,
+            up: true,
+        });
+        assert_eq!(
+            ip(&rules.active_at(0), "github.com").unwrap(),
+            "140.82.113.4"
+        );
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut rules = rules(
+            "140.82.113.4 github.com\n",
+            &["github.com=140.82.114.4@19:00-23:30"],
+        );
+        let rule = RuleValue::from("140.82.112.4".parse::<Ipv4Addr>().unwrap());
+
+        // over every layer and window, whatever the case of the host
+        rules.set_override(DEFAULT_POLICY, "GitHub.com.", Some(rule));
+        assert_eq!(
+            ip(&rules.active_at(12 * 60), "github.com").unwrap(),
+            "140.82.112.4"
+        );
+        assert_eq!(
+            ip(&rules.active_at(20 * 60), "github.com").unwrap(),
+            "140.82.112.4"
+        );
+        assert!(rules.scheduled().is_empty());
+
+        rules.set_override(DEFAULT_POLICY, "github.com", None);
+        assert_eq!(ip(&rules.active_at(12 * 60), "github.com"), None);
+        assert_eq!(ip(&rules.active_at(20 * 60), "github.com"), None);
+    }
+}
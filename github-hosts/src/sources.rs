@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use github_hosts_common::hosts::{self, Diagnostic, Entry, Window};
+
+/// host -> candidate ips in the order they were listed, merged from every
+/// configured source
+pub type Hosts = BTreeMap<String, Vec<Ipv4Addr>>;
+
+/// A host as listed in one source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Listed {
+    /// Candidates, in the order they were listed
+    pub ips: Vec<Ipv4Addr>,
+    /// When the rule applies, from `#@active`, or always
+    pub window: Option<Window>,
+}
+
+/// policy -> host -> how it's listed, the policy set with `#@policy`
+pub type Policies = BTreeMap<u32, BTreeMap<String, Listed>>;
+
+/// A parser diagnostic together with the file it was found in.
+#[derive(Debug)]
+pub struct FileDiagnostic {
+    pub path: PathBuf,
+    pub diagnostic: Diagnostic,
+}
+
+impl fmt::Display for FileDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.diagnostic)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Loaded {
+    pub hosts: Policies,
+    pub diagnostics: Vec<FileDiagnostic>,
+}
+
+/// Load and merge hosts from `paths`.
+///
+/// Sources are applied in the order they are given and a later entry for the
+/// same host overrides an earlier one, both across sources and within a
+/// single file. Within a `#@candidates on` block of a file, the addresses of a
+/// host are instead all its candidates, the first one being used unless the
+/// prober finds a faster one. A directory contributes every regular,
+/// non-hidden file in it, in file name order, so
+/// `/etc/github-hosts/hosts.d/10-base` is overridden by
+/// `/etc/github-hosts/hosts.d/50-local`.
+///
+/// Only unreadable sources are fatal; malformed lines are skipped and
+/// reported in [`Loaded::diagnostics`].
+pub fn load(paths: &[PathBuf]) -> Result<Loaded, anyhow::Error> {
+    let mut loaded = Loaded::default();
+    for path in paths {
+        for file in expand(path)? {
+            load_file(&file, &mut loaded)?;
+        }
+    }
+    Ok(loaded)
+}
+
+/// Expand a source path into the list of files to read.
+fn expand(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let meta = std::fs::metadata(path)
+        .with_context(|| format!("failed to read hosts source {}", path.display()))?;
+    if !meta.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = vec![];
+    let entries = std::fs::read_dir(path)
+        .with_context(|| format!("failed to list hosts directory {}", path.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // follow symlinks, the usual way to enable a file in a conf.d directory
+        if std::fs::metadata(entry.path())?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn load_file(path: &Path, loaded: &mut Loaded) -> Result<(), anyhow::Error> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read hosts file {}", path.display()))?;
+    let file = hosts::parse(&content);
+    let mut diagnostics = file.diagnostics;
+    for (policy, hosts) in from_entries(file.entries, &mut diagnostics) {
+        loaded.hosts.entry(policy).or_default().extend(hosts);
+    }
+    diagnostics.sort_by_key(|d| d.line);
+    loaded
+        .diagnostics
+        .extend(diagnostics.into_iter().map(|diagnostic| FileDiagnostic {
+            path: path.to_path_buf(),
+            diagnostic,
+        }));
+    Ok(())
+}
+
+/// Group the IPv4 entries of one file by policy and host, warning about the
+/// IPv6 ones. A later entry replaces the earlier ones of its host, a
+/// candidate is added to them, keeping the window of the first.
+pub fn from_entries(entries: Vec<Entry>, diagnostics: &mut Vec<Diagnostic>) -> Policies {
+    let mut hosts = Policies::new();
+    for entry in entries {
+        match entry.addr {
+            IpAddr::V4(ip) => {
+                let listed = hosts
+                    .entry(entry.policy)
+                    .or_default()
+                    .entry(entry.host.clone())
+                    .or_default();
+                if !entry.candidate {
+                    *listed = Listed {
+                        ips: vec![ip],
+                        window: entry.window,
+                    };
+                    continue;
+                }
+                if listed.ips.is_empty() {
+                    listed.window = entry.window;
+                } else if listed.window != entry.window {
+                    diagnostics.push(Diagnostic::warning(
+                        entry.line,
+                        format!(
+                            "`{}` is listed with another time window before, keeping that one",
+                            entry.host
+                        ),
+                    ));
+                }
+                listed.ips.push(ip);
+            }
+            IpAddr::V6(ip) => diagnostics.push(Diagnostic::warning(
+                entry.line,
+                format!(
+                    "skipping {} {}, only A records are rewritten",
+                    ip, entry.host
+                ),
+            )),
+        }
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use github_hosts_common::DEFAULT_POLICY;
+
+    #[test]
+    fn test_precedence() {
+        let dir = std::env::temp_dir().join(format!("github-hosts-sources-{}", std::process::id()));
+        let hosts_d = dir.join("hosts.d");
+        std::fs::create_dir_all(&hosts_d).unwrap();
+        std::fs::write(
+            dir.join("base.txt"),
+            "# base\n1.1.1.1 github.com\n1.1.1.2 api.github.com\nbogus\n1.1.1.3 api.github.com\n\
+             #@candidates on\n1.1.1.4 gist.github.com\n1.1.1.5 gist.github.com\n",
+        )
+        .unwrap();
+        std::fs::write(hosts_d.join("10-a"), "2.2.2.1 github.com\n").unwrap();
+        std::fs::write(
+            hosts_d.join("20-b"),
+            "#@candidates on\n3.3.3.1 github.com\n3.3.3.2 github.com\n",
+        )
+        .unwrap();
+        std::fs::write(hosts_d.join(".hidden"), "4.4.4.4 github.com\n").unwrap();
+
+        let loaded = load(&[dir.join("base.txt"), hosts_d]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let hosts = &loaded.hosts[&DEFAULT_POLICY];
+        assert_eq!(
+            hosts["github.com"].ips,
+            [Ipv4Addr::new(3, 3, 3, 1), Ipv4Addr::new(3, 3, 3, 2)]
+        );
+        // the later line wins outside of `#@candidates on`
+        assert_eq!(hosts["api.github.com"].ips, [Ipv4Addr::new(1, 1, 1, 3)]);
+        assert_eq!(
+            hosts["gist.github.com"].ips,
+            [Ipv4Addr::new(1, 1, 1, 4), Ipv4Addr::new(1, 1, 1, 5)]
+        );
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(loaded.diagnostics.len(), 2);
+        assert_eq!(
+            loaded.diagnostics[0].to_string(),
+            format!(
+                "{}:4: error: invalid address `bogus`",
+                dir.join("base.txt").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_fixture() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/hosts.txt");
+        let hosts = &load(&[fixture]).unwrap().hosts[&DEFAULT_POLICY];
+        assert_eq!(hosts["google.com"].ips, [Ipv4Addr::new(1, 2, 3, 4)]);
+        assert_eq!(
+            hosts["github.githubassets.com"].ips,
+            [Ipv4Addr::new(4, 3, 2, 1)]
+        );
+        assert_eq!(hosts["123.com"].ips, [Ipv4Addr::new(4, 4, 4, 4)]);
+    }
+}
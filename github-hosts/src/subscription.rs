@@ -0,0 +1,225 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use log::{info, warn};
+use tokio::sync::mpsc::UnboundedSender;
+
+use github_hosts_common::hosts;
+
+use crate::rules::Update;
+use crate::sources::{self, Policies};
+
+/// A remote hosts list, such as the one published by ineo6/hosts, and the
+/// on-disk copy of the last version of it that passed validation.
+pub struct Subscription {
+    pub url: String,
+    cache: PathBuf,
+    client: reqwest::Client,
+    last: Option<Policies>,
+}
+
+impl Subscription {
+    pub fn new(url: &str, cache_dir: &Path) -> Result<Self, anyhow::Error> {
+        Ok(Subscription {
+            url: url.to_string(),
+            cache: cache_dir.join(cache_name(url)),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()
+                .context("failed to build HTTP client")?,
+            last: None,
+        })
+    }
+
+    /// Load the last good copy from disk, so rules are available before the
+    /// first fetch succeeds (or when the network is down at startup).
+    pub fn load_cache(&mut self) -> Option<Policies> {
+        let content = std::fs::read_to_string(&self.cache).ok()?;
+        match validate(&content) {
+            Ok(hosts) => {
+                self.last = Some(hosts.clone());
+                Some(hosts)
+            }
+            Err(e) => {
+                warn!("ignoring cached copy {}: {:#}", self.cache.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Fetch and validate the list. Returns the new rules if they differ
+    /// from the last good copy, which is then replaced on disk.
+    pub async fn refresh(&mut self) -> Result<Option<Policies>, anyhow::Error> {
+        let content = self
+            .client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let hosts = validate(&content)?;
+        if self.last.as_ref() == Some(&hosts) {
+            return Ok(None);
+        }
+        if let Err(e) = save(&self.cache, &content) {
+            warn!("failed to save {}: {:#}", self.cache.display(), e);
+        }
+        self.last = Some(hosts.clone());
+        Ok(Some(hosts))
+    }
+}
+
+/// One readable file name per URL, e.g.
+/// `https___gitlab.com_ineo6_hosts_-_raw_master_hosts-5a974d26`. The hash of
+/// the whole URL tells apart the URLs that read the same once sanitized.
+fn cache_name(url: &str) -> String {
+    let mut name: String = url
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    // FNV-1a, stable across builds unlike std's hasher
+    let hash = url.bytes().fold(0x811c9dc5u32, |h, b| {
+        (h ^ b as u32).wrapping_mul(0x01000193)
+    });
+    name += &format!("-{:08x}", hash);
+    name
+}
+
+/// Reject anything that is not a clean hosts file, e.g. an HTML error page
+/// served with a 200 status, so a bad fetch never replaces good rules.
+fn validate(content: &str) -> Result<Policies, anyhow::Error> {
+    let file = hosts::parse(content);
+    if let Some(d) = file
+        .diagnostics
+        .iter()
+        .find(|d| d.severity == hosts::Severity::Error)
+    {
+        bail!("invalid hosts list, line {}", d);
+    }
+    let hosts = sources::from_entries(file.entries, &mut vec![]);
+    if hosts.is_empty() {
+        bail!("hosts list has no IPv4 entries");
+    }
+    Ok(hosts)
+}
+
+/// Write through a temporary file so a crash never leaves a truncated copy.
+fn save(path: &Path, content: &str) -> Result<(), anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Refresh every subscription in the background, sending changed lists to `tx`.
+pub fn spawn(subscriptions: Vec<Subscription>, interval: Duration, tx: UnboundedSender<Update>) {
+    for (index, mut sub) in subscriptions.into_iter().enumerate() {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match sub.refresh().await {
+                    Ok(Some(hosts)) => {
+                        let rules: usize = hosts.values().map(|h| h.len()).sum();
+                        info!("{}: {} rules", sub.url, rules);
+                        if tx.send(Update::Remote { index, hosts }).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => info!("{}: unchanged", sub.url),
+                    Err(e) => warn!(
+                        "{}: refresh failed, keeping last good copy: {:#}",
+                        sub.url, e
+                    ),
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use github_hosts_common::DEFAULT_POLICY;
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// A stand-in for the hosts list server answering each request with the
+    /// next of `bodies`.
+    async fn serve(bodies: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hosts", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_cache_name() {
+        let name = cache_name("https://example.com/a?b");
+        assert!(name.starts_with("https___example.com_a_b-"));
+        assert_ne!(name, cache_name("https://example.com/a_b"));
+        assert_ne!(
+            cache_name("http://example.com/hosts"),
+            cache_name("https://example.com/hosts")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let url = serve(vec![
+            "140.82.113.4 github.com\n",
+            "140.82.113.4 github.com\n",
+            "<html>502 Bad Gateway</html>\n",
+            "140.82.114.4 github.com\n",
+        ])
+        .await;
+        let dir = std::env::temp_dir().join(format!("github-hosts-sub-{}", std::process::id()));
+        let mut sub = Subscription::new(&url, &dir).unwrap();
+
+        let hosts = sub.refresh().await.unwrap().unwrap();
+        assert_eq!(
+            hosts[&DEFAULT_POLICY]["github.com"].ips,
+            [Ipv4Addr::new(140, 82, 113, 4)]
+        );
+        // unchanged
+        assert!(sub.refresh().await.unwrap().is_none());
+        // garbage is rejected and the good copy stays on disk
+        assert!(sub.refresh().await.is_err());
+        let mut restarted = Subscription::new(&url, &dir).unwrap();
+        assert_eq!(restarted.load_cache(), Some(hosts));
+
+        let hosts = sub.refresh().await.unwrap().unwrap();
+        assert_eq!(
+            hosts[&DEFAULT_POLICY]["github.com"].ips,
+            [Ipv4Addr::new(140, 82, 114, 4)]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}